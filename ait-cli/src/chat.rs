@@ -0,0 +1,123 @@
+use ait_lib::gpt::{
+    ChatCompletionOptions, CompletionBackend, EmbeddingBackend, GptClient, GptEmbedding,
+};
+use ait_lib::prompt::{PromptBuilder, PromptOptions};
+use ait_lib::utils::TextId;
+use clap::Args;
+use futures_util::{pin_mut, StreamExt};
+use std::io::{self, BufRead, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{CliStore, Result};
+
+#[derive(Debug, Args)]
+pub struct ChatArgs {
+    /// The chat model to prompt.
+    #[arg(long, default_value = "gpt-3.5-turbo")]
+    model: String,
+    /// Substituted for `{user_name}` in the system prompt.
+    #[arg(long, env = "USER")]
+    user_name: Option<String>,
+}
+
+/// Today's date in UTC, in the format `YYYY-MM-DD`.
+fn today() -> Option<String> {
+    let days = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs() / 86_400;
+    Some(date_from_days(days as i64))
+}
+
+/// The date `days` after the Unix epoch, in the format `YYYY-MM-DD`.
+fn date_from_days(days: i64) -> String {
+    // shift the epoch to 0000-03-01 so that leap days end each 4 year cycle
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Answer each line read from stdin, with the related experiences as
+/// context, and push each exchange to the history in the `store`. The
+/// history is locked while each query is answered, and reloaded for the next
+/// one, so that the updates of other processes are kept.
+pub async fn chat(store: &CliStore, client: &GptClient, args: ChatArgs) -> Result<()> {
+    let prompt_options = PromptOptions {
+        model: args.model.into(),
+        user_name: args.user_name,
+        ..Default::default()
+    };
+    let chat_options = ChatCompletionOptions {
+        model: prompt_options.model.clone(),
+        ..Default::default()
+    };
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+        let query = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        let query = query.trim();
+        if query.is_empty() {
+            continue;
+        }
+
+        let lock = store.lock()?;
+        let mut history = lock.load()?;
+        let embedding: GptEmbedding = client.embed(query).await?;
+        let options = PromptOptions {
+            date: today(),
+            ..prompt_options.clone()
+        };
+        let prompt = PromptBuilder::new(&history, options).build(query, &embedding)?;
+
+        let stream = client
+            .chat_completion_stream(prompt.messages, &chat_options)
+            .await?;
+        pin_mut!(stream);
+        let mut response = String::new();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            print!("{}", chunk);
+            io::stdout().flush()?;
+            response.push_str(&chunk);
+        }
+        println!("\n");
+
+        let included: Vec<TextId> = prompt
+            .included
+            .iter()
+            .filter_map(|x| prompt.context.get(*x))
+            .copied()
+            .collect();
+        history.record_retrieval(&included);
+        let embedding: GptEmbedding = client.embed(&format!("{}\n\n{}", query, response)).await?;
+        history.push(query, &response, embedding, prompt.context)?;
+        lock.store(&history)?;
+    }
+    println!();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn formats_dates() {
+        assert_eq!(date_from_days(0), "1970-01-01");
+        assert_eq!(date_from_days(11_016), "2000-02-29");
+        assert_eq!(date_from_days(19_417), "2023-03-01");
+    }
+}
@@ -0,0 +1,470 @@
+use ait_lib::export::{self, VectorEncoding};
+use ait_lib::gpt::{
+    self, EmbeddingBackend, GptClient, GptClientConfig, GptEmbedding, RetryPolicy, EMBED_DIMS,
+    EMBED_MODEL,
+};
+use ait_lib::history::{
+    self, Experience, History, MergePolicy, Related, RelatedOptions, Scoring, Search,
+};
+use ait_lib::prompt;
+use ait_lib::store::{self, FileStore, FileStoreLock, HistoryStore};
+use ait_lib::utils::{text_id_to_hex, TextId};
+use base64::{engine::general_purpose, Engine};
+use clap::{Parser, Subcommand};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+mod chat;
+
+/// The number of hexadecimal digits shown for text IDs in listings.
+const SHORT_ID_LEN: usize = 12;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("no OpenAI API token, set it with --token or OPENAI_API_KEY")]
+    MissingToken,
+    #[error("no experience with ID {0}")]
+    NoSuchExperience(String),
+    #[error("more than one experience has an ID starting with {0}")]
+    AmbiguousId(String),
+    #[error("the history at {0} isn't empty, use --force to replace it")]
+    HistoryNotEmpty(PathBuf),
+    #[error("failed to decode the exported history")]
+    InvalidExport,
+    #[error("{}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+    #[error(transparent)]
+    Terminal(#[from] io::Error),
+    #[error(transparent)]
+    HistoryError(#[from] history::Error),
+    #[error(transparent)]
+    ExportError(#[from] export::Error),
+    #[error("{}: {source}", path.display())]
+    StoreError { path: PathBuf, source: store::Error },
+    #[error(transparent)]
+    GptError(#[from] gpt::Error),
+    #[error(transparent)]
+    PromptError(#[from] prompt::Error),
+}
+
+type Result<T> = core::result::Result<T, Error>;
+
+pub type CliHistory = History<EMBED_DIMS>;
+
+#[derive(Debug, Parser)]
+#[command(name = "ait", version, about = "Query and manage an Ait history")]
+struct Args {
+    /// The history file. It is created when missing, and stored as JSON if
+    /// its extension is `json`.
+    #[arg(long, env = "AIT_HISTORY", default_value = "ait_history")]
+    history: PathBuf,
+    /// The OpenAI API token, needed by commands which embed or chat.
+    #[arg(long, env = "OPENAI_API_KEY", hide_env_values = true)]
+    token: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Chat interactively, remembering each exchange in the history.
+    Chat(chat::ChatArgs),
+    /// Add an exchange to the history.
+    Push {
+        query: String,
+        response: String,
+        /// Link to this experience (repeatable). By default, links to the
+        /// experiences related to the query.
+        #[arg(long = "link", value_name = "ID")]
+        links: Vec<String>,
+        /// The most experiences to link to by default.
+        #[arg(long, default_value_t = 128)]
+        context: usize,
+    },
+    /// List the experiences, oldest first.
+    List,
+    /// Show an experience, given its ID or a unique prefix of it.
+    Show { id: String },
+    /// List the experiences related to some text, most related first.
+    Related {
+        text: String,
+        /// The most experiences to list.
+        #[arg(short, long, default_value_t = 8)]
+        num: usize,
+        /// Score every experience instead of following links from the last
+        /// one, and report the recall of following links.
+        #[arg(long)]
+        exhaustive: bool,
+        /// Leave out experiences further than this cosine distance.
+        #[arg(long)]
+        max_distance: Option<f32>,
+        /// Re-rank to leave out near duplicates, from 1 for relevance alone to
+        /// 0 for diversity alone.
+        #[arg(long)]
+        mmr_lambda: Option<f32>,
+        /// Favour recent experiences, by up to this much cosine distance.
+        #[arg(long)]
+        recency_weight: Option<f32>,
+        /// The experiences added for the favour shown to an experience to
+        /// halve.
+        #[arg(long, requires = "recency_weight")]
+        half_life: Option<f32>,
+        /// Favour often retrieved experiences, by up to this much cosine
+        /// distance.
+        #[arg(long)]
+        retrieval_weight: Option<f32>,
+    },
+    /// Remove an experience, given its ID or a unique prefix of it.
+    Delete { id: String },
+    /// Write the history, encoded as in the web demo's storage.
+    Export {
+        /// Write to this file instead of stdout.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Write readable JSON instead.
+        #[arg(long)]
+        json: bool,
+        /// Write the embeddings in JSON as base64 instead of arrays.
+        #[arg(long, requires = "json")]
+        base64_vectors: bool,
+    },
+    /// Replace the history with one written by `export`, in either format.
+    Import {
+        /// Read from this file instead of stdin.
+        input: Option<PathBuf>,
+        /// Replace the history even if it isn't empty.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Add the experiences of a history written by `export` to the history.
+    Merge {
+        /// Read from this file instead of stdin.
+        input: Option<PathBuf>,
+        /// Place the experiences before the history's instead of after.
+        #[arg(long)]
+        prepend: bool,
+    },
+}
+
+/// Keeps the history in a file, and reports errors with its path.
+pub struct CliStore(FileStore);
+
+impl CliStore {
+    /// Load the history, noting the model which makes its embeddings if it
+    /// isn't known.
+    pub fn load(&self) -> Result<CliHistory> {
+        let history = self.0.load_or_default().map_err(|e| self.error(e))?;
+        Ok(with_embedding_model(history))
+    }
+
+    /// Lock the history until the returned lock is dropped, so that it can be
+    /// loaded, modified and stored without losing the updates of other
+    /// processes.
+    pub fn lock(&self) -> Result<CliStoreLock<'_>> {
+        let lock = self.0.lock().map_err(|e| self.error(e))?;
+        Ok(CliStoreLock { store: self, lock })
+    }
+
+    fn error(&self, source: store::Error) -> Error {
+        Error::StoreError {
+            path: self.0.path().to_path_buf(),
+            source,
+        }
+    }
+}
+
+/// A history locked by `CliStore::lock`.
+pub struct CliStoreLock<'a> {
+    store: &'a CliStore,
+    lock: FileStoreLock<'a>,
+}
+
+impl CliStoreLock<'_> {
+    /// Like `CliStore::load`.
+    pub fn load(&self) -> Result<CliHistory> {
+        let history = self
+            .lock
+            .load_or_default()
+            .map_err(|e| self.store.error(e))?;
+        Ok(with_embedding_model(history))
+    }
+
+    pub fn store(&self, history: &CliHistory) -> Result<()> {
+        self.lock.store(history).map_err(|e| self.store.error(e))
+    }
+}
+
+fn with_embedding_model(mut history: CliHistory) -> CliHistory {
+    if history.embedding_model().is_none() {
+        history.set_embedding_model(Some(EMBED_MODEL.name().to_string()));
+    }
+    history
+}
+
+/// A client which retries transient failures, such as rate limiting.
+pub fn client(token: Option<&str>) -> Result<GptClient> {
+    let token = token.ok_or(Error::MissingToken)?;
+    let config = GptClientConfig {
+        retry: Some(RetryPolicy::default()),
+        ..Default::default()
+    };
+    Ok(GptClient::with_config(token, config)?)
+}
+
+/// Find the experience whose ID is, or starts with, the hexadecimal `prefix`.
+fn find_id(history: &CliHistory, prefix: &str) -> Result<TextId> {
+    let prefix = prefix.to_lowercase();
+    let mut ids = history
+        .iter()
+        .map(|x| x.id)
+        .filter(|x| text_id_to_hex(x).starts_with(&prefix));
+    match (ids.next(), ids.next()) {
+        (Some(id), None) if !prefix.is_empty() => Ok(id),
+        (Some(_), _) => Err(Error::AmbiguousId(prefix)),
+        (None, _) => Err(Error::NoSuchExperience(prefix)),
+    }
+}
+
+/// The first line of `text`, shortened to `len` characters.
+fn headline(text: &str, len: usize) -> String {
+    let line = text.lines().next().unwrap_or_default();
+    match line.char_indices().nth(len) {
+        Some((end, _)) => format!("{}...", &line[..end]),
+        None => line.to_string(),
+    }
+}
+
+fn print_summary(experience: &Experience<EMBED_DIMS>, distance: Option<f32>) {
+    let id = text_id_to_hex(&experience.id);
+    let distance = distance.map(|x| format!("{:.3}  ", x)).unwrap_or_default();
+    println!(
+        "{}  {:>5}  {}{}",
+        &id[..SHORT_ID_LEN],
+        experience.rank,
+        distance,
+        headline(&experience.query, 60)
+    );
+}
+
+fn read_input(input: Option<&Path>) -> Result<String> {
+    let mut text = String::new();
+    match input {
+        Some(path) => {
+            text = std::fs::read_to_string(path).map_err(|source| Error::Io {
+                path: path.to_path_buf(),
+                source,
+            })?
+        }
+        None => {
+            io::stdin().read_to_string(&mut text)?;
+        }
+    }
+    Ok(text)
+}
+
+/// Decode a history written by `export`, as JSON or as base64. A history
+/// file stored as JSON is also accepted.
+fn decode_export(data: &str) -> Result<CliHistory> {
+    if data.trim_start().starts_with('{') {
+        return Ok(CliHistory::read_json(data)?);
+    }
+    let data = general_purpose::STANDARD_NO_PAD
+        .decode(data.trim())
+        .map_err(|_| Error::InvalidExport)?;
+    Ok(CliHistory::deserialize(&data)?)
+}
+
+async fn run(args: Args) -> Result<()> {
+    let token = args.token.as_deref();
+    let store = CliStore(FileStore::new(&args.history));
+    match args.command {
+        Command::Chat(chat_args) => {
+            chat::chat(&store, &client(token)?, chat_args).await?;
+        }
+        Command::Push {
+            query,
+            response,
+            links,
+            context,
+        } => {
+            let lock = store.lock()?;
+            let mut history = lock.load()?;
+            let links = links
+                .iter()
+                .map(|x| find_id(&history, x))
+                .collect::<Result<Vec<TextId>>>()?;
+            let client = client(token)?;
+            let exchange = format!("{}\n\n{}", query, response);
+            let mut embeddings: Vec<GptEmbedding> =
+                client.embed_batch(&[query.as_str(), &exchange]).await?;
+            let embedding = embeddings.pop().ok_or(gpt::Error::EmptyResponse)?;
+            let links = match (links.is_empty(), embeddings.pop()) {
+                (true, Some(query_embedding)) => history
+                    .related(&query_embedding, context)?
+                    .into_iter()
+                    .map(|x| x.id)
+                    .collect(),
+                _ => links,
+            };
+            let id = history.push(&query, &response, embedding, links)?;
+            lock.store(&history)?;
+            println!("{}", text_id_to_hex(&id));
+        }
+        Command::List => {
+            let history = store.load()?;
+            let mut experiences: Vec<_> = history.iter().collect();
+            experiences.sort_by_key(|x| x.rank);
+            for experience in experiences {
+                print_summary(experience, None);
+            }
+        }
+        Command::Show { id } => {
+            let history = store.load()?;
+            let id = find_id(&history, &id)?;
+            let experience = history
+                .get(&id)
+                .ok_or_else(|| Error::NoSuchExperience(text_id_to_hex(&id)))?;
+            println!("id: {}", text_id_to_hex(&id));
+            println!("rank: {}", experience.rank);
+            for link in history.links(&id).unwrap_or_default() {
+                println!("link: {}", text_id_to_hex(link));
+            }
+            println!("\n{}\n\n{}", experience.query, experience.response);
+        }
+        Command::Related {
+            text,
+            num,
+            exhaustive,
+            max_distance,
+            mmr_lambda,
+            recency_weight,
+            half_life,
+            retrieval_weight,
+        } => {
+            let history = store.load()?;
+            let embedding: GptEmbedding = client(token)?.embed(&text).await?;
+            let scoring = (recency_weight.is_some() || retrieval_weight.is_some()).then(|| {
+                let default = Scoring::default();
+                Scoring {
+                    recency_weight: recency_weight.unwrap_or(0.0),
+                    half_life: half_life.unwrap_or(default.half_life),
+                    retrieval_weight: retrieval_weight.unwrap_or(0.0),
+                }
+            });
+            let linked = RelatedOptions {
+                max_distance,
+                mmr_lambda,
+                scoring,
+                ..Default::default()
+            };
+            let exhaustive_options = RelatedOptions {
+                search: Search::Exhaustive,
+                ..linked.clone()
+            };
+            let related = history.related_with(&embedding, num, &linked)?;
+            let listed = if exhaustive {
+                history.related_with(&embedding, num, &exhaustive_options)?
+            } else {
+                related.clone()
+            };
+            for x in &listed {
+                if let Some(experience) = history.get(&x.id) {
+                    print_summary(experience, Some(x.distance));
+                }
+            }
+            if exhaustive {
+                let ids = |x: &[Related]| x.iter().map(|x| x.id).collect::<Vec<_>>();
+                let recall = history::recall(&ids(&related), &ids(&listed));
+                println!("Recall of links: {:.2}", recall);
+            }
+        }
+        Command::Delete { id } => {
+            let lock = store.lock()?;
+            let mut history = lock.load()?;
+            let id = find_id(&history, &id)?;
+            history.remove(&id);
+            lock.store(&history)?;
+        }
+        Command::Export {
+            output,
+            json,
+            base64_vectors,
+        } => {
+            let history = store.load()?;
+            let data = match (json, base64_vectors) {
+                (true, true) => history.export_json(VectorEncoding::Base64)?,
+                (true, false) => history.export_json(VectorEncoding::Array)?,
+                (false, _) => general_purpose::STANDARD_NO_PAD.encode(history.serialize()?),
+            };
+            match output {
+                Some(path) => {
+                    std::fs::write(&path, data).map_err(|source| Error::Io { path, source })?
+                }
+                None => writeln!(io::stdout(), "{}", data)?,
+            }
+        }
+        Command::Import { input, force } => {
+            let imported = decode_export(&read_input(input.as_deref())?)?;
+            let lock = store.lock()?;
+            if !force && !lock.load()?.is_empty() {
+                return Err(Error::HistoryNotEmpty(args.history));
+            }
+            lock.store(&imported)?;
+            println!("Imported {} experiences.", imported.len());
+        }
+        Command::Merge { input, prepend } => {
+            let other = decode_export(&read_input(input.as_deref())?)?;
+            let policy = if prepend {
+                MergePolicy::Prepend
+            } else {
+                MergePolicy::Append
+            };
+            let lock = store.lock()?;
+            let mut history = lock.load()?;
+            let added = history.merge(other, policy);
+            lock.store(&history)?;
+            println!("Merged {} new experiences.", added);
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(Args::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ait_lib::embedding::Embedding;
+
+    #[test]
+    fn finds_ids_by_prefix() {
+        let mut history = CliHistory::new();
+        let embedding = Embedding::new("", [0.0; EMBED_DIMS]);
+        let id = history.push("q1", "r1", embedding, vec![]).unwrap();
+        let hex = text_id_to_hex(&id);
+        assert_eq!(find_id(&history, &hex[..6]).unwrap(), id);
+        assert_eq!(find_id(&history, &hex.to_uppercase()).unwrap(), id);
+        assert!(matches!(find_id(&history, ""), Err(Error::AmbiguousId(_))));
+        assert!(matches!(
+            find_id(&history, "xyz"),
+            Err(Error::NoSuchExperience(_))
+        ));
+    }
+
+    #[test]
+    fn shortens_headlines() {
+        assert_eq!(headline("abc\ndef", 5), "abc");
+        assert_eq!(headline("abcdef", 3), "abc...");
+        assert_eq!(headline("", 3), "");
+    }
+}
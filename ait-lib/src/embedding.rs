@@ -1,4 +1,4 @@
-use crate::utils::{new_text_id, TextId};
+use crate::utils::{new_text_id, TextId, TextIdHex};
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use thiserror;
@@ -18,6 +18,7 @@ pub type Vector<const N: usize> = [f32; N];
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Embedding<const N: usize> {
+    #[serde_as(as = "TextIdHex")]
     id: TextId,
     #[serde_as(as = "[_; N]")]
     vector: Vector<N>,
@@ -29,6 +30,20 @@ impl<const N: usize> Embedding<N> {
         Self { id, vector }
     }
 
+    /// Rebuild an embedding from its `id` and `vector`, as kept by `new`.
+    pub fn from_parts(id: TextId, vector: Vector<N>) -> Self {
+        Self { id, vector }
+    }
+
+    /// The ID of the text which was embedded.
+    pub fn id(&self) -> &TextId {
+        &self.id
+    }
+
+    pub fn vector(&self) -> &Vector<N> {
+        &self.vector
+    }
+
     fn norm(&self) -> f32 {
         self.vector.iter().map(|x| x * x).sum::<f32>().sqrt()
     }
@@ -0,0 +1,341 @@
+//! A readable JSON form of a history, for reviewing, diffing and keeping
+//! curated histories under version control.
+
+use base64::{engine::general_purpose, Engine};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+
+use crate::embedding::{Embedding, Vector};
+use crate::history::{self, Experience, History, HistoryMetadata, LinkedExperience};
+use crate::hnsw::HnswParams;
+use crate::store::{StoreFormat, FORMAT_VERSION};
+use crate::utils::{new_text_id, text_id_to_hex, TextId, TextIdHex};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to write the JSON history: {0}")]
+    CantExport(String),
+    #[error("failed to read the JSON history: {0}")]
+    CantImport(String),
+    #[error("the JSON history has format version {version}, which this version of Ait can't read")]
+    UnsupportedVersion { version: u32 },
+    #[error(
+        "the embedding of experience {id} has {dims} dimensions, but {expected} were expected"
+    )]
+    IncompatibleEmbedding {
+        id: String,
+        dims: usize,
+        expected: usize,
+    },
+    #[error("the embedding of experience {0} isn't valid base64")]
+    InvalidEmbedding(String),
+}
+
+type Result<T> = core::result::Result<T, Error>;
+
+/// How embedding vectors are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VectorEncoding {
+    /// Arrays of numbers, which are readable but large.
+    #[default]
+    Array,
+    /// Base64 of the little-endian bytes of the numbers, which is about a
+    /// third of the size.
+    Base64,
+}
+
+/// An embedding vector, written as either an array or base64. Both are read.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum JsonVector {
+    Array(Vec<f32>),
+    Base64(String),
+}
+
+impl JsonVector {
+    fn new(vector: &[f32], encoding: VectorEncoding) -> Self {
+        match encoding {
+            VectorEncoding::Array => JsonVector::Array(vector.to_vec()),
+            VectorEncoding::Base64 => {
+                let bytes: Vec<u8> = vector.iter().flat_map(|x| x.to_le_bytes()).collect();
+                JsonVector::Base64(general_purpose::STANDARD_NO_PAD.encode(bytes))
+            }
+        }
+    }
+
+    fn into_vector<const N: usize>(self, id: &str) -> Result<Vector<N>> {
+        let values = match self {
+            JsonVector::Array(values) => values,
+            JsonVector::Base64(data) => general_purpose::STANDARD_NO_PAD
+                .decode(data.trim_end_matches('='))
+                .map_err(|_| Error::InvalidEmbedding(id.to_string()))?
+                .chunks(4)
+                .map(|x| x.try_into().map(f32::from_le_bytes))
+                .collect::<core::result::Result<Vec<_>, _>>()
+                .map_err(|_| Error::InvalidEmbedding(id.to_string()))?,
+        };
+        let dims = values.len();
+        values.try_into().map_err(|_| Error::IncompatibleEmbedding {
+            id: id.to_string(),
+            dims,
+            expected: N,
+        })
+    }
+}
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonExperience {
+    #[serde_as(as = "TextIdHex")]
+    id: TextId,
+    rank: u32,
+    query: String,
+    response: String,
+    #[serde_as(as = "Vec<TextIdHex>")]
+    #[serde(default)]
+    links: Vec<TextId>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    retrievals: u32,
+    #[serde_as(as = "TextIdHex")]
+    embedding_id: TextId,
+    embedding: JsonVector,
+}
+
+fn is_zero(x: &u32) -> bool {
+    *x == 0
+}
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonHistory {
+    version: u32,
+    #[serde(default)]
+    embedding_model: Option<String>,
+    #[serde(default)]
+    created_at: Option<u64>,
+    #[serde(default)]
+    persona: Option<String>,
+    #[serde_as(as = "Option<TextIdHex>")]
+    #[serde(default)]
+    last_id: Option<TextId>,
+    next_rank: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    index: Option<HnswParams>,
+    /// Ordered by rank, so that exports of a growing history diff cleanly.
+    experiences: Vec<JsonExperience>,
+}
+
+impl<const N: usize> History<N> {
+    /// Write the history as indented JSON, with its experiences in the order
+    /// they were added.
+    pub fn export_json(&self, vectors: VectorEncoding) -> Result<String> {
+        let metadata = self.metadata();
+        let mut experiences: Vec<JsonExperience> = self
+            .iter()
+            .map(|x| JsonExperience {
+                id: x.id,
+                rank: x.rank,
+                query: x.query.clone(),
+                response: x.response.clone(),
+                links: self.links(&x.id).unwrap_or_default().to_vec(),
+                retrievals: self.retrievals(&x.id).unwrap_or_default(),
+                embedding_id: *x.embedding.id(),
+                embedding: JsonVector::new(x.embedding.vector(), vectors),
+            })
+            .collect();
+        experiences.sort_by_key(|x| x.rank);
+        let json = JsonHistory {
+            version: FORMAT_VERSION,
+            embedding_model: metadata.embedding_model,
+            created_at: metadata.created_at,
+            persona: metadata.persona,
+            last_id: metadata.last_id,
+            next_rank: metadata.next_rank,
+            index: metadata.index,
+            experiences,
+        };
+        serde_json::to_string_pretty(&json).map_err(|e| Error::CantExport(e.to_string()))
+    }
+
+    /// Read a history written by `export_json`. Embeddings may be given as
+    /// arrays or base64, whatever the encoding they were exported with.
+    ///
+    /// IDs are recomputed from the exchanges, so that an edited exchange gets
+    /// the ID which `push` would give it, and links to it follow. Links to
+    /// missing experiences and ranks which aren't unique or below `next_rank`
+    /// are rejected.
+    pub fn import_json(json: &str) -> Result<Self> {
+        let json: JsonHistory =
+            serde_json::from_str(json).map_err(|e| Error::CantImport(e.to_string()))?;
+        if json.version > FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion {
+                version: json.version,
+            });
+        }
+        let ids: HashMap<TextId, TextId> = json
+            .experiences
+            .iter()
+            .map(|x| (x.id, new_text_id(&[&x.query, &x.response])))
+            .collect();
+        let unique: HashSet<&TextId> = ids.values().collect();
+        if ids.len() != json.experiences.len() || unique.len() != ids.len() {
+            return Err(Error::CantImport(
+                "an experience is in the history more than once".to_string(),
+            ));
+        }
+        let id = |x: &TextId| {
+            ids.get(x).copied().ok_or_else(|| {
+                Error::CantImport(format!("no experience with ID {}", text_id_to_hex(x)))
+            })
+        };
+        let next_rank = json.next_rank;
+        let mut ranks = HashSet::new();
+        let experiences = json
+            .experiences
+            .into_iter()
+            .map(|x| {
+                let hex = text_id_to_hex(&x.id);
+                if x.rank >= next_rank || !ranks.insert(x.rank) {
+                    return Err(Error::CantImport(format!(
+                        "experience {} has rank {}, which is taken or not below {}",
+                        hex, x.rank, next_rank
+                    )));
+                }
+                let vector = x.embedding.into_vector(&hex)?;
+                Ok(LinkedExperience {
+                    experience: Experience {
+                        id: id(&x.id)?,
+                        embedding: Embedding::from_parts(x.embedding_id, vector),
+                        query: x.query,
+                        response: x.response,
+                        rank: x.rank,
+                    },
+                    links: x.links.iter().map(id).collect::<Result<_>>()?,
+                    retrievals: x.retrievals,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let metadata = HistoryMetadata {
+            last_id: json.last_id.as_ref().map(id).transpose()?,
+            next_rank: json.next_rank,
+            persona: json.persona,
+            embedding_model: json.embedding_model,
+            created_at: json.created_at,
+            index: json.index,
+            index_entry: None,
+        };
+        Ok(History::from_parts(metadata, experiences))
+    }
+}
+
+impl<const N: usize> History<N> {
+    /// Read a history written as JSON, either by `export_json` or by a store
+    /// in the JSON format. Exports are told apart by their list of
+    /// experiences, which stores keep as a map.
+    pub fn read_json(json: &str) -> core::result::Result<Self, history::Error> {
+        let value: serde_json::Value =
+            serde_json::from_str(json).map_err(|e| Error::CantImport(e.to_string()))?;
+        if value["experiences"].is_array() {
+            Ok(Self::import_json(json)?)
+        } else {
+            Ok(StoreFormat::Json.deserialize(json.as_bytes())?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exports_and_imports_json() {
+        let mut history = History::<2>::new();
+        history.set_persona(Some("A persona".to_string()));
+        let id1 = history
+            .push("q1", "r1", Embedding::new("q1", [0.5, 1.0]), vec![])
+            .unwrap();
+        let id2 = history
+            .push("q2", "r2", Embedding::new("q2", [1.0, -0.25]), vec![id1])
+            .unwrap();
+        history.record_retrieval(&[id1]);
+
+        for vectors in [VectorEncoding::Array, VectorEncoding::Base64] {
+            let json = history.export_json(vectors).unwrap();
+            assert!(json.find("\"q1\"").unwrap() < json.find("\"q2\"").unwrap());
+            let imported = History::<2>::import_json(&json).unwrap();
+            assert_eq!(imported.persona(), Some("A persona"));
+            assert_eq!(imported.links(&id2).unwrap(), [id1]);
+            assert_eq!(imported.retrievals(&id1), Some(1));
+            let experience = imported.get(&id2).unwrap();
+            assert_eq!(experience.response, "r2");
+            assert_eq!(experience.embedding.vector(), &[1.0, -0.25]);
+            assert_eq!(
+                experience.embedding.id(),
+                history.get(&id2).unwrap().embedding.id()
+            );
+        }
+    }
+
+    #[test]
+    fn reads_exports_and_stores_as_json() {
+        let mut history = History::<2>::new();
+        let id = history
+            .push("q1", "r1", Embedding::new("q1", [0.5, 1.0]), vec![])
+            .unwrap();
+        let export = history.export_json(VectorEncoding::Array).unwrap();
+        let stored = StoreFormat::Json.serialize(&history).unwrap();
+        let stored = String::from_utf8(stored).unwrap();
+        for json in [export, stored] {
+            assert!(History::<2>::read_json(&json).unwrap().get(&id).is_some());
+        }
+    }
+
+    #[test]
+    fn checks_edited_json() {
+        let mut history = History::<2>::new();
+        let id1 = history
+            .push("q1", "r1", Embedding::new("q1", [0.5, 1.0]), vec![])
+            .unwrap();
+        history
+            .push("q2", "r2", Embedding::new("q2", [1.0, -0.25]), vec![id1])
+            .unwrap();
+        let json = history.export_json(VectorEncoding::Array).unwrap();
+
+        // the edited response gets a new ID, which the link follows
+        let edited = json.replace("\"r1\"", "\"r1 corrected\"");
+        let imported = History::<2>::import_json(&edited).unwrap();
+        let id = new_text_id(&["q1", "r1 corrected"]);
+        assert!(imported.get(&id1).is_none());
+        let id2 = new_text_id(&["q2", "r2"]);
+        assert_eq!(imported.links(&id2).unwrap(), [id]);
+
+        // the link is the last mention of the first experience
+        let at = json.rfind(&text_id_to_hex(&id1)).unwrap();
+        let mut dangling = json.clone();
+        dangling.replace_range(at..at + 64, &text_id_to_hex(&[0; 32]));
+        assert!(matches!(
+            History::<2>::import_json(&dangling),
+            Err(Error::CantImport(_))
+        ));
+        let ranks = json.replace("\"next_rank\": 2", "\"next_rank\": 1");
+        assert!(matches!(
+            History::<2>::import_json(&ranks),
+            Err(Error::CantImport(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_embeddings_of_other_dimensions() {
+        let mut history = History::<2>::new();
+        history
+            .push("q1", "r1", Embedding::new("q1", [0.5, 1.0]), vec![])
+            .unwrap();
+        let json = history.export_json(VectorEncoding::Array).unwrap();
+        assert!(matches!(
+            History::<3>::import_json(&json),
+            Err(Error::IncompatibleEmbedding { dims: 2, .. })
+        ));
+    }
+}
@@ -1,28 +1,132 @@
 //! Interact with OpenAI's GPT models.
 
+use eventsource_stream::{EventStreamError, Eventsource};
+use futures_util::{future, stream, Stream, StreamExt};
+use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, skip_serializing_none};
+use std::future::Future;
+use std::time::Duration;
 use tap::Pipe;
-use thiserror;
 
 use crate::embedding::Embedding;
+use crate::utils::{random, sleep};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    #[error("failed to request text completion")]
-    InvalidTextCompletion,
-    #[error("failed to request chat completion")]
-    InvalidChatCompletion,
-    #[error("failed to request embedding")]
-    InvalidEmbedding,
+    #[error("the API rejected the credentials: {message}")]
+    Unauthorized { message: String },
+    #[error("the API rate limit was reached: {message}")]
+    RateLimited {
+        retry_after: Option<Duration>,
+        message: String,
+    },
+    #[error("the prompt exceeds the model's context length: {message}")]
+    ContextLengthExceeded { message: String },
+    #[error("the API responded with status {status}: {message}")]
+    Api { status: u16, message: String },
+    #[error("failed to reach the API: {0}")]
+    Network(reqwest::Error),
+    #[error("failed to decode the API response: {0}")]
+    Decode(String),
+    #[error("the API response contains no result")]
+    EmptyResponse,
     #[error("failed to serailize embedding")]
     CantSerialize,
     #[error("failed to de-serailize embedding")]
     CantDeserialize,
+    #[error("failed to build the client")]
+    InvalidClientConfig,
+}
+
+impl Error {
+    /// A short name for the kind of error, which doesn't vary with the message.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Unauthorized { .. } => "Unauthorized",
+            Error::RateLimited { .. } => "RateLimited",
+            Error::ContextLengthExceeded { .. } => "ContextLengthExceeded",
+            Error::Api { .. } => "ApiError",
+            Error::Network(_) => "NetworkError",
+            Error::Decode(_) => "DecodeError",
+            Error::EmptyResponse => "EmptyResponse",
+            Error::CantSerialize | Error::CantDeserialize => "SerializationError",
+            Error::InvalidClientConfig => "InvalidClientConfig",
+        }
+    }
+}
+
+impl Error {
+    /// Whether the same request might succeed if sent again later.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::RateLimited { .. } => true,
+            Error::Api { status, .. } => *status >= 500,
+            Error::Network(e) => e.is_timeout() || e.is_connect(),
+            _ => false,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_decode() {
+            Error::Decode(e.to_string())
+        } else {
+            Error::Network(e)
+        }
+    }
 }
 
 type Result<T> = core::result::Result<T, Error>;
 
+/// The body of an API response which reports an error.
+#[derive(Debug, Deserialize)]
+struct ApiErrorResponse {
+    error: ApiError,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiError {
+    message: String,
+    code: Option<String>,
+}
+
+/// Parse the `Retry-After` header given in seconds. The HTTP date form is not
+/// used by the OpenAI API.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(RETRY_AFTER)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.trim().parse::<f64>().ok())
+        .filter(|x| x.is_finite() && *x >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+/// Build the error for a response with a non-success `status`, given its
+/// `headers` and `body`.
+fn error_from_response(status: StatusCode, headers: &HeaderMap, body: String) -> Error {
+    let (message, code) = match serde_json::from_str::<ApiErrorResponse>(&body) {
+        Ok(ApiErrorResponse { error }) => (error.message, error.code),
+        Err(_) => (body, None),
+    };
+    match status {
+        StatusCode::UNAUTHORIZED => Error::Unauthorized { message },
+        StatusCode::TOO_MANY_REQUESTS => Error::RateLimited {
+            retry_after: retry_after(headers),
+            message,
+        },
+        _ if code.as_deref() == Some("context_length_exceeded") => {
+            Error::ContextLengthExceeded { message }
+        }
+        _ => Error::Api {
+            status: status.as_u16(),
+            message,
+        },
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 enum FinishReason {
@@ -54,19 +158,45 @@ struct TextCompletionResponse {
     choices: Vec<TextCompletionChoice>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-enum TextCompletionModel {
-    #[serde(rename = "text-davinci-003")]
+/// A model which continues a prompt. Models not known to this crate can be
+/// named with `Other`.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum TextCompletionModel {
+    #[default]
     GptDavinci003,
+    Other(String),
+}
+
+impl TextCompletionModel {
+    pub fn name(&self) -> &str {
+        match self {
+            TextCompletionModel::GptDavinci003 => "text-davinci-003",
+            TextCompletionModel::Other(name) => name,
+        }
+    }
+}
+
+impl From<String> for TextCompletionModel {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "text-davinci-003" => TextCompletionModel::GptDavinci003,
+            _ => TextCompletionModel::Other(name),
+        }
+    }
+}
+
+impl From<TextCompletionModel> for String {
+    fn from(model: TextCompletionModel) -> Self {
+        model.name().to_string()
+    }
 }
 
 #[derive(Debug, Serialize)]
-#[skip_serializing_none]
 struct TextCompletionRequest<'a> {
-    model: TextCompletionModel,
     prompt: &'a str,
-    max_tokens: Option<u16>,
-    temperature: Option<f32>,
+    #[serde(flatten)]
+    options: &'a TextCompletionOptions,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -91,6 +221,15 @@ pub struct ChatCompletionMessage {
     pub content: String,
 }
 
+impl ChatCompletionMessage {
+    pub fn system(content: &str) -> Self {
+        Self {
+            role: ChatCompletionMessageRole::System,
+            content: content.to_string(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct ChatCompletionChoice {
     message: ChatCompletionMessage,
@@ -103,23 +242,131 @@ struct ChatCompletionResponse {
     choices: Vec<ChatCompletionChoice>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-enum ChatCompletionModel {
-    #[serde(rename = "gpt-3.5-turbo")]
-    Gpt35Turbot,
-    #[serde(rename = "gpt-3.5-turbo")]
-    Gpt35Turbot0301,
+/// A model which responds to a chat history. Models not known to this crate
+/// can be named with `Other`.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum ChatCompletionModel {
+    #[default]
+    Gpt35Turbo,
+    Gpt35Turbo0301,
+    Gpt4,
+    Gpt4_32k,
+    Other(String),
 }
 
-#[derive(Debug, Serialize)]
+impl ChatCompletionModel {
+    pub fn name(&self) -> &str {
+        match self {
+            ChatCompletionModel::Gpt35Turbo => "gpt-3.5-turbo",
+            ChatCompletionModel::Gpt35Turbo0301 => "gpt-3.5-turbo-0301",
+            ChatCompletionModel::Gpt4 => "gpt-4",
+            ChatCompletionModel::Gpt4_32k => "gpt-4-32k",
+            ChatCompletionModel::Other(name) => name,
+        }
+    }
+
+    /// The most tokens the model can process, counting both its prompt and its
+    /// response. `None` for models not known to this crate.
+    pub fn context_window(&self) -> Option<usize> {
+        match self {
+            ChatCompletionModel::Gpt35Turbo => Some(4096),
+            ChatCompletionModel::Gpt35Turbo0301 => Some(4096),
+            ChatCompletionModel::Gpt4 => Some(8192),
+            ChatCompletionModel::Gpt4_32k => Some(32768),
+            ChatCompletionModel::Other(_) => None,
+        }
+    }
+}
+
+impl From<String> for ChatCompletionModel {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "gpt-3.5-turbo" => ChatCompletionModel::Gpt35Turbo,
+            "gpt-3.5-turbo-0301" => ChatCompletionModel::Gpt35Turbo0301,
+            "gpt-4" => ChatCompletionModel::Gpt4,
+            "gpt-4-32k" => ChatCompletionModel::Gpt4_32k,
+            _ => ChatCompletionModel::Other(name),
+        }
+    }
+}
+
+impl From<ChatCompletionModel> for String {
+    fn from(model: ChatCompletionModel) -> Self {
+        model.name().to_string()
+    }
+}
+
+/// The generation parameters of a completion request. Parameters left as
+/// `None` are not sent, and take the API's default value.
 #[skip_serializing_none]
-struct ChatCompletionRequest {
-    model: ChatCompletionModel,
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompletionOptions<M> {
+    pub model: M,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub stop: Option<Vec<String>>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub seed: Option<i64>,
+}
+
+impl<M: Default> Default for CompletionOptions<M> {
+    fn default() -> Self {
+        Self {
+            model: M::default(),
+            temperature: Some(0.0),
+            top_p: None,
+            max_tokens: Some(2048),
+            stop: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            seed: None,
+        }
+    }
+}
+
+pub type TextCompletionOptions = CompletionOptions<TextCompletionModel>;
+pub type ChatCompletionOptions = CompletionOptions<ChatCompletionModel>;
+
+#[skip_serializing_none]
+#[derive(Debug, Serialize)]
+struct ChatCompletionRequest<'a> {
     messages: Vec<ChatCompletionMessage>,
-    max_tokens: Option<u16>,
-    temperature: Option<f32>,
+    #[serde(flatten)]
+    options: &'a ChatCompletionOptions,
+    stream: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum ChatCompletionChunkObjectValue {
+    #[serde(rename = "chat.completion.chunk")]
+    ChatCompletionChunk,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatCompletionDelta {
+    role: Option<ChatCompletionMessageRole>,
+    content: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatCompletionChunkChoice {
+    delta: ChatCompletionDelta,
+    finish_reason: Option<FinishReason>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatCompletionChunk {
+    object: ChatCompletionChunkObjectValue,
+    choices: Vec<ChatCompletionChunkChoice>,
 }
 
+/// The data of the server-sent event which terminates a chat completion stream.
+const STREAM_DONE: &str = "[DONE]";
+
 #[derive(Debug, Serialize, Deserialize)]
 enum EmbeddingObjectValue {
     #[serde(rename = "embedding")]
@@ -132,9 +379,19 @@ pub enum EmbeddingModel {
     TextEmbeddingAda002,
 }
 
+impl EmbeddingModel {
+    pub fn name(&self) -> &str {
+        match self {
+            EmbeddingModel::TextEmbeddingAda002 => "text-embedding-ada-002",
+        }
+    }
+}
+
 #[serde_as]
 #[derive(Debug, Serialize, Deserialize)]
 struct EmbeddingData {
+    object: EmbeddingObjectValue,
+    index: usize,
     #[serde_as(as = "[_; 1536]")]
     embedding: [f32; 1536],
 }
@@ -145,73 +402,20 @@ struct EmbeddingResponse {
     data: Vec<EmbeddingData>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize)]
 struct EmbeddingRequest<'a> {
     model: EmbeddingModel,
-    input: &'a str,
+    input: &'a [&'a str],
 }
 
-/// Generate a continuation for the given `prompt`.
-pub async fn text_completion(token: &str, prompt: &str) -> Result<String> {
-    reqwest::Client::new()
-        .post("https://api.openai.com/v1/completions")
-        .bearer_auth(token)
-        .json(&TextCompletionRequest {
-            model: TextCompletionModel::GptDavinci003,
-            prompt,
-            max_tokens: Some(2048),
-            temperature: Some(0.0),
-        })
-        .send()
-        .await
-        .map_err(|_| Error::InvalidTextCompletion)?
-        .json::<TextCompletionResponse>()
-        .await
-        .ok()
-        .and_then(|x| x.choices.into_iter().next().map(|x| x.text))
-        .ok_or(Error::InvalidTextCompletion)?
-        .pipe(Ok)
-}
-
-const SYSTEM_MESSAGE: &'static str = "\
-You are Ait, a helpful AI assistant. \
-You have extensive knowledge of many facts documented on the world wide web. \
-However, you are not able to perfectly recall those facts. \
-When you are unsure about a detail, do not attempt to provide an answer. \
-You instead state that you do not know the answer.\
-";
-
-/// Generate a response for the chat history given by `messages`.
-pub async fn chat_completion(token: &str, messages: Vec<ChatCompletionMessage>) -> Result<String> {
-    let messages = {
-        let mut messages = messages;
-        messages.insert(
-            0,
-            ChatCompletionMessage {
-                role: ChatCompletionMessageRole::System,
-                content: SYSTEM_MESSAGE.to_string(),
-            },
-        );
-        messages
-    };
-    reqwest::Client::new()
-        .post("https://api.openai.com/v1/chat/completions")
-        .bearer_auth(token)
-        .json(&ChatCompletionRequest {
-            model: ChatCompletionModel::Gpt35Turbot0301,
-            messages,
-            max_tokens: Some(2048),
-            temperature: Some(0.0),
-        })
-        .send()
-        .await
-        .map_err(|_| Error::InvalidChatCompletion)?
-        .json::<ChatCompletionResponse>()
-        .await
-        .ok()
-        .and_then(|x| x.choices.into_iter().next().map(|x| x.message.content))
-        .ok_or(Error::InvalidChatCompletion)?
-        .pipe(Ok)
+/// Parse the data of one server-sent event into the content it adds to the
+/// response. Events which carry no content (e.g. the role announcement) yield
+/// `None`.
+fn parse_chat_completion_chunk(data: &str) -> Option<Result<String>> {
+    serde_json::from_str::<ChatCompletionChunk>(data)
+        .map_err(|e| Error::Decode(e.to_string()))
+        .map(|x| x.choices.into_iter().next().and_then(|x| x.delta.content))
+        .transpose()
 }
 
 pub const fn embedding_model_size(model: EmbeddingModel) -> usize {
@@ -220,26 +424,739 @@ pub const fn embedding_model_size(model: EmbeddingModel) -> usize {
     }
 }
 
-pub const EMBED_DIMS: usize = embedding_model_size(EmbeddingModel::TextEmbeddingAda002);
+/// The model which makes the embeddings of `GptClient::embed`.
+pub const EMBED_MODEL: EmbeddingModel = EmbeddingModel::TextEmbeddingAda002;
+pub const EMBED_DIMS: usize = embedding_model_size(EMBED_MODEL);
 pub type GptEmbedding = Embedding<EMBED_DIMS>;
 
-/// Generate an embedding for the given `text`.
-pub async fn embed(token: &str, text: &str) -> Result<GptEmbedding> {
-    reqwest::Client::new()
-        .post("https://api.openai.com/v1/embeddings")
-        .bearer_auth(token)
-        .json(&EmbeddingRequest {
-            model: EmbeddingModel::TextEmbeddingAda002,
-            input: text,
+/// `Send` with the `native` feature, so that servers can share backends
+/// between threads. The browser client isn't `Send`, so nothing is required
+/// otherwise.
+#[cfg(feature = "native")]
+pub trait MaybeSend: Send {}
+#[cfg(feature = "native")]
+impl<T: Send + ?Sized> MaybeSend for T {}
+#[cfg(not(feature = "native"))]
+pub trait MaybeSend {}
+#[cfg(not(feature = "native"))]
+impl<T: ?Sized> MaybeSend for T {}
+
+/// `Sync` with the `native` feature, as for `MaybeSend`.
+#[cfg(feature = "native")]
+pub trait MaybeSync: Sync {}
+#[cfg(feature = "native")]
+impl<T: Sync + ?Sized> MaybeSync for T {}
+#[cfg(not(feature = "native"))]
+pub trait MaybeSync {}
+#[cfg(not(feature = "native"))]
+impl<T: ?Sized> MaybeSync for T {}
+
+/// A provider of text and chat completions.
+///
+/// The futures are only required to be `Send` with the `native` feature,
+/// since the browser client isn't. Implementations can use `async fn`.
+pub trait CompletionBackend: MaybeSync {
+    /// Generate a continuation for the given `prompt`.
+    fn text_completion(
+        &self,
+        prompt: &str,
+        options: &TextCompletionOptions,
+    ) -> impl Future<Output = Result<String>> + MaybeSend;
+
+    /// Generate a response for the chat history given by `messages`. Any
+    /// system prompt must be included in `messages`.
+    fn chat_completion(
+        &self,
+        messages: Vec<ChatCompletionMessage>,
+        options: &ChatCompletionOptions,
+    ) -> impl Future<Output = Result<String>> + MaybeSend;
+
+    /// Generate a response for the chat history given by `messages`, yielding
+    /// the response content as it is generated.
+    ///
+    /// Backends which can't stream yield the full response as a single item.
+    fn chat_completion_stream(
+        &self,
+        messages: Vec<ChatCompletionMessage>,
+        options: &ChatCompletionOptions,
+    ) -> impl Future<Output = Result<impl Stream<Item = Result<String>>>> + MaybeSend {
+        async move {
+            let response = self.chat_completion(messages, options).await?;
+            Ok(stream::once(future::ready(Ok(response))))
+        }
+    }
+}
+
+/// A provider of embeddings with `N` dimensions.
+pub trait EmbeddingBackend<const N: usize>: MaybeSync {
+    /// Generate an embedding for the given `text`.
+    fn embed(&self, text: &str) -> impl Future<Output = Result<Embedding<N>>> + MaybeSend;
+
+    /// Generate an embedding for each of the given `texts`, in the same order.
+    ///
+    /// Backends which can't embed many texts at once embed them one by one.
+    fn embed_batch(
+        &self,
+        texts: &[&str],
+    ) -> impl Future<Output = Result<Vec<Embedding<N>>>> + MaybeSend {
+        async move {
+            let mut embeddings = Vec::with_capacity(texts.len());
+            for text in texts {
+                embeddings.push(self.embed(text).await?);
+            }
+            Ok(embeddings)
+        }
+    }
+}
+
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
+/// How to retry requests which fail with a transient error (rate limiting,
+/// server errors and dropped connections).
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// The most requests to send, including the first one.
+    pub max_attempts: u32,
+    /// The delay before the first retry. It doubles with each retry.
+    pub base_delay: Duration,
+    /// The longest delay between two attempts.
+    pub max_delay: Duration,
+    /// The fraction of each delay, between 0 and 1, which is randomly removed
+    /// so that concurrent clients don't retry in lockstep.
+    pub jitter: f64,
+    /// Wait at least as long as the `Retry-After` sent by the API.
+    pub honor_retry_after: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.5,
+            honor_retry_after: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before sending attempt number `attempt + 1`, following the
+    /// failure of attempt number `attempt` with `error`. Attempts count from 1.
+    fn delay(&self, attempt: u32, error: &Error) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        let jitter = self.jitter.clamp(0.0, 1.0) * random();
+        let backoff = backoff.mul_f64(1.0 - jitter);
+        match error {
+            Error::RateLimited {
+                retry_after: Some(retry_after),
+                ..
+            } if self.honor_retry_after => backoff.max(*retry_after),
+            _ => backoff,
+        }
+    }
+}
+
+/// Settings for a `GptClient`.
+#[derive(Debug, Clone)]
+pub struct GptClientConfig {
+    /// The URL under which the API endpoints are found.
+    pub base_url: String,
+    /// Sent as the `OpenAI-Organization` header when set.
+    pub organization: Option<String>,
+    /// Abandon requests which take longer than this. Ignored in the browser,
+    /// which has no request timeouts.
+    pub timeout: Option<Duration>,
+    /// Sent with every request (e.g. Azure's `api-key`).
+    pub headers: HeaderMap,
+    /// Appended to every request URL (e.g. Azure's `api-version`).
+    pub query: Vec<(String, String)>,
+    /// Retry requests which fail with a transient error. Requests are sent
+    /// only once when `None`.
+    pub retry: Option<RetryPolicy>,
+    /// The most texts to embed in a single request.
+    pub embedding_batch_size: usize,
+}
+
+impl Default for GptClientConfig {
+    fn default() -> Self {
+        Self {
+            base_url: DEFAULT_BASE_URL.to_string(),
+            organization: None,
+            timeout: None,
+            headers: HeaderMap::new(),
+            query: Vec::new(),
+            retry: None,
+            embedding_batch_size: 256,
+        }
+    }
+}
+
+/// A client for the OpenAI API, or any API compatible with it.
+///
+/// The `token` is sent as a bearer token, unless it is empty.
+#[derive(Debug, Clone)]
+pub struct GptClient {
+    token: String,
+    base_url: String,
+    query: Vec<(String, String)>,
+    retry: Option<RetryPolicy>,
+    embedding_batch_size: usize,
+    client: reqwest::Client,
+}
+
+impl GptClient {
+    pub fn new(token: &str) -> Self {
+        Self::with_config(token, GptClientConfig::default())
+            .expect("default client config is valid")
+    }
+
+    pub fn with_config(token: &str, config: GptClientConfig) -> Result<Self> {
+        let mut headers = config.headers;
+        if let Some(organization) = &config.organization {
+            let organization =
+                HeaderValue::from_str(organization).map_err(|_| Error::InvalidClientConfig)?;
+            headers.insert("OpenAI-Organization", organization);
+        }
+        let builder = reqwest::Client::builder().default_headers(headers);
+        #[cfg(not(target_arch = "wasm32"))]
+        let builder = match config.timeout {
+            Some(timeout) => builder.timeout(timeout),
+            None => builder,
+        };
+        let client = builder.build().map_err(|_| Error::InvalidClientConfig)?;
+        Ok(Self {
+            token: token.to_string(),
+            base_url: config.base_url.trim_end_matches('/').to_string(),
+            query: config.query,
+            retry: config.retry,
+            embedding_batch_size: config.embedding_batch_size.max(1),
+            client,
         })
-        .send()
-        .await
-        .map_err(|_| Error::InvalidEmbedding)?
-        .json::<EmbeddingResponse>()
-        .await
-        .ok()
-        .and_then(|x| x.data.into_iter().next())
-        .map(|x| GptEmbedding::new(text, x.embedding))
-        .ok_or(Error::InvalidEmbedding)?
-        .pipe(Ok)
+    }
+
+    fn post(&self, path: &str) -> reqwest::RequestBuilder {
+        let request = self
+            .client
+            .post(format!("{}/{}", self.base_url, path))
+            .query(&self.query);
+        if self.token.is_empty() {
+            request
+        } else {
+            request.bearer_auth(&self.token)
+        }
+    }
+
+    /// Send the `request`, and convert unsuccessful responses into errors.
+    /// Transient errors are retried according to the client's retry policy.
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let policy = match &self.retry {
+            Some(policy) => policy,
+            None => return Self::send_once(request).await,
+        };
+        let mut attempt = 1;
+        loop {
+            let retry = match request.try_clone() {
+                Some(retry) if attempt < policy.max_attempts => retry,
+                _ => return Self::send_once(request).await,
+            };
+            match Self::send_once(retry).await {
+                Err(e) if e.is_transient() => sleep(policy.delay(attempt, &e)).await,
+                result => return result,
+            }
+            attempt += 1;
+        }
+    }
+
+    async fn send_once(request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let headers = response.headers().clone();
+        let body = response.text().await?;
+        Err(error_from_response(status, &headers, body))
+    }
+}
+
+impl CompletionBackend for GptClient {
+    async fn text_completion(
+        &self,
+        prompt: &str,
+        options: &TextCompletionOptions,
+    ) -> Result<String> {
+        let request = self
+            .post("completions")
+            .json(&TextCompletionRequest { prompt, options });
+        self.send(request)
+            .await?
+            .json::<TextCompletionResponse>()
+            .await?
+            .choices
+            .into_iter()
+            .next()
+            .map(|x| x.text)
+            .ok_or(Error::EmptyResponse)
+    }
+
+    async fn chat_completion(
+        &self,
+        messages: Vec<ChatCompletionMessage>,
+        options: &ChatCompletionOptions,
+    ) -> Result<String> {
+        let request = self.post("chat/completions").json(&ChatCompletionRequest {
+            messages,
+            options,
+            stream: None,
+        });
+        self.send(request)
+            .await?
+            .json::<ChatCompletionResponse>()
+            .await?
+            .choices
+            .into_iter()
+            .next()
+            .map(|x| x.message.content)
+            .ok_or(Error::EmptyResponse)
+    }
+
+    async fn chat_completion_stream(
+        &self,
+        messages: Vec<ChatCompletionMessage>,
+        options: &ChatCompletionOptions,
+    ) -> Result<impl Stream<Item = Result<String>>> {
+        let request = self.post("chat/completions").json(&ChatCompletionRequest {
+            messages,
+            options,
+            stream: Some(true),
+        });
+        self.send(request)
+            .await?
+            .bytes_stream()
+            .eventsource()
+            .take_while(|x| future::ready(!matches!(x, Ok(event) if event.data == STREAM_DONE)))
+            .filter_map(|x| {
+                future::ready(match x {
+                    Ok(event) => parse_chat_completion_chunk(&event.data),
+                    Err(EventStreamError::Transport(e)) => Some(Err(e.into())),
+                    Err(e) => Some(Err(Error::Decode(e.to_string()))),
+                })
+            })
+            .pipe(Ok)
+    }
+}
+
+impl EmbeddingBackend<EMBED_DIMS> for GptClient {
+    async fn embed(&self, text: &str) -> Result<GptEmbedding> {
+        self.embed_batch(&[text])
+            .await?
+            .pop()
+            .ok_or(Error::EmptyResponse)
+    }
+
+    async fn embed_batch(&self, texts: &[&str]) -> Result<Vec<GptEmbedding>> {
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(self.embedding_batch_size) {
+            let request = self.post("embeddings").json(&EmbeddingRequest {
+                model: EMBED_MODEL,
+                input: batch,
+            });
+            let mut data = self
+                .send(request)
+                .await?
+                .json::<EmbeddingResponse>()
+                .await?
+                .data;
+            if data.len() != batch.len() {
+                Err(Error::Decode(format!(
+                    "expected {} embeddings but received {}",
+                    batch.len(),
+                    data.len()
+                )))?;
+            }
+            // the API doesn't promise to respond in input order
+            data.sort_by_key(|x| x.index);
+            embeddings.extend(
+                batch
+                    .iter()
+                    .zip(data)
+                    .map(|(text, x)| GptEmbedding::new(text, x.embedding)),
+            );
+        }
+        Ok(embeddings)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_chat_completion_chunks() {
+        let role = r#"{"object":"chat.completion.chunk","choices":[{"delta":{"role":"assistant"},"finish_reason":null}]}"#;
+        let content = r#"{"object":"chat.completion.chunk","choices":[{"delta":{"content":"Hi"},"finish_reason":null}]}"#;
+        let stop =
+            r#"{"object":"chat.completion.chunk","choices":[{"delta":{},"finish_reason":"stop"}]}"#;
+        assert!(parse_chat_completion_chunk(role).is_none());
+        assert_eq!(parse_chat_completion_chunk(content).unwrap().unwrap(), "Hi");
+        assert!(parse_chat_completion_chunk(stop).is_none());
+        assert!(parse_chat_completion_chunk("{}").unwrap().is_err());
+    }
+
+    #[test]
+    fn serializes_chat_completion_options() {
+        let options = ChatCompletionOptions {
+            model: "my-model".to_string().into(),
+            stop: Some(vec!["\n".to_string()]),
+            seed: Some(1),
+            ..Default::default()
+        };
+        let request = ChatCompletionRequest {
+            messages: vec![],
+            options: &options,
+            stream: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&request).unwrap(),
+            serde_json::json!({
+                "messages": [],
+                "model": "my-model",
+                "temperature": 0.0,
+                "max_tokens": 2048,
+                "stop": ["\n"],
+                "seed": 1,
+            })
+        );
+        let options: ChatCompletionOptions =
+            serde_json::from_str(r#"{"model": "gpt-4", "top_p": 0.5}"#).unwrap();
+        assert_eq!(options.model, ChatCompletionModel::Gpt4);
+        assert_eq!(options.top_p, Some(0.5));
+        assert_eq!(options.max_tokens, Some(2048));
+    }
+
+    #[test]
+    fn retry_delay_honors_retry_after() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(3),
+            jitter: 0.0,
+            ..Default::default()
+        };
+        let error = Error::RateLimited {
+            retry_after: Some(Duration::from_secs(10)),
+            message: String::new(),
+        };
+        assert_eq!(
+            policy.delay(1, &Error::EmptyResponse),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            policy.delay(2, &Error::EmptyResponse),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            policy.delay(5, &Error::EmptyResponse),
+            Duration::from_secs(3)
+        );
+        assert_eq!(policy.delay(1, &error), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn retries_only_transient_errors() {
+        let error = Error::Api {
+            status: 503,
+            message: String::new(),
+        };
+        assert!(error.is_transient());
+        let error = Error::Api {
+            status: 400,
+            message: String::new(),
+        };
+        assert!(!error.is_transient());
+        // a request which can't be built fails the same way every time
+        let error: Error = reqwest::Client::new()
+            .get("not a url")
+            .build()
+            .unwrap_err()
+            .into();
+        assert!(!error.is_transient());
+    }
+
+    struct FakeBackend;
+
+    impl CompletionBackend for FakeBackend {
+        async fn text_completion(
+            &self,
+            prompt: &str,
+            _options: &TextCompletionOptions,
+        ) -> Result<String> {
+            Ok(prompt.to_string())
+        }
+
+        async fn chat_completion(
+            &self,
+            messages: Vec<ChatCompletionMessage>,
+            _options: &ChatCompletionOptions,
+        ) -> Result<String> {
+            messages
+                .into_iter()
+                .last()
+                .map(|x| x.content)
+                .ok_or(Error::EmptyResponse)
+        }
+    }
+
+    impl EmbeddingBackend<2> for FakeBackend {
+        async fn embed(&self, text: &str) -> Result<Embedding<2>> {
+            Ok(Embedding::new(text, [text.len() as f32, 1.0]))
+        }
+    }
+
+    #[test]
+    fn fake_backend_streams_full_response() {
+        let backend = FakeBackend;
+        let messages = vec![ChatCompletionMessage {
+            role: ChatCompletionMessageRole::User,
+            content: "echo".to_string(),
+        }];
+        let chunks: Vec<Result<String>> = futures_executor::block_on(async {
+            backend
+                .chat_completion_stream(messages, &ChatCompletionOptions::default())
+                .await
+                .unwrap()
+                .collect()
+                .await
+        });
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].as_ref().unwrap(), "echo");
+        let embedding = futures_executor::block_on(backend.embed("abc")).unwrap();
+        assert!(
+            embedding
+                .cosine_distance(&Embedding::new("", [3.0, 1.0]))
+                .abs()
+                < 1e-6
+        );
+    }
+
+    /// Tests against a stub server on a local port.
+    #[cfg(all(feature = "native", not(target_arch = "wasm32")))]
+    mod server {
+        use super::*;
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::thread::{self, JoinHandle};
+
+        /// Answer one request with each of `responses` (status line, headers and
+        /// body) on a local port. Returns the base URL to request, and a handle
+        /// which joins to the received requests.
+        fn serve(responses: Vec<String>) -> (String, JoinHandle<Vec<String>>) {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let base_url = format!("http://{}/v1", listener.local_addr().unwrap());
+            let handle = thread::spawn(move || {
+                let mut requests = Vec::new();
+                for response in responses {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let mut request = Vec::new();
+                    let mut buffer = [0u8; 4096];
+                    loop {
+                        let n = stream.read(&mut buffer).unwrap();
+                        request.extend_from_slice(&buffer[..n]);
+                        let text = String::from_utf8_lossy(&request).to_string();
+                        if let Some(end) = text.find("\r\n\r\n") {
+                            let length = text
+                                .lines()
+                                .filter_map(|x| {
+                                    x.to_lowercase()
+                                        .strip_prefix("content-length: ")
+                                        .map(|x| x.parse::<usize>().unwrap())
+                                })
+                                .next()
+                                .unwrap_or(0);
+                            if request.len() >= end + 4 + length || n == 0 {
+                                break;
+                            }
+                        }
+                    }
+                    requests.push(String::from_utf8_lossy(&request).to_string());
+                    stream.write_all(response.as_bytes()).unwrap();
+                }
+                requests
+            });
+            (base_url, handle)
+        }
+
+        fn json_response(status: &str, body: &str) -> String {
+            format!(
+                "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+        }
+
+        fn embedding_body(value: f32) -> String {
+            embeddings_body(&[(0, value)])
+        }
+
+        /// A response body with one embedding for each `(index, value)`, whose
+        /// vector is `value` in its first dimension and 1 in the others.
+        fn embeddings_body(embeddings: &[(usize, f32)]) -> String {
+            let data: Vec<serde_json::Value> = embeddings
+                .iter()
+                .map(|(index, value)| {
+                    let mut vector = vec![1.0; EMBED_DIMS];
+                    vector[0] = *value;
+                    serde_json::json!({"object": "embedding", "index": index, "embedding": vector})
+                })
+                .collect();
+            serde_json::json!({"object": "list", "data": data}).to_string()
+        }
+
+        #[tokio::test]
+        async fn client_reports_api_errors() {
+            let context_length = r#"{"error": {"message": "too long", "type": "invalid_request_error", "code": "context_length_exceeded"}}"#;
+            let (base_url, handle) = serve(vec![
+                json_response("401 Unauthorized", r#"{"error": {"message": "bad key", "code": null}}"#),
+                "HTTP/1.1 429 Too Many Requests\r\nretry-after: 2\r\ncontent-length: 4\r\nconnection: close\r\n\r\nslow".to_string(),
+                json_response("400 Bad Request", context_length),
+                json_response("500 Internal Server Error", "oops"),
+                json_response("200 OK", "{}"),
+            ]);
+            let config = GptClientConfig {
+                base_url,
+                ..Default::default()
+            };
+            let client = GptClient::with_config("token", config).unwrap();
+            match client.embed("text").await {
+                Err(Error::Unauthorized { message }) => assert_eq!(message, "bad key"),
+                x => panic!("unexpected result: {:?}", x),
+            }
+            match client.embed("text").await {
+                Err(Error::RateLimited {
+                    retry_after,
+                    message,
+                }) => {
+                    assert_eq!(retry_after, Some(Duration::from_secs(2)));
+                    assert_eq!(message, "slow");
+                }
+                x => panic!("unexpected result: {:?}", x),
+            }
+            match client.embed("text").await {
+                Err(Error::ContextLengthExceeded { message }) => assert_eq!(message, "too long"),
+                x => panic!("unexpected result: {:?}", x),
+            }
+            match client.embed("text").await {
+                Err(Error::Api { status, message }) => {
+                    assert_eq!(status, 500);
+                    assert_eq!(message, "oops");
+                }
+                x => panic!("unexpected result: {:?}", x),
+            }
+            match client.embed("text").await {
+                Err(Error::Decode(_)) => {}
+                x => panic!("unexpected result: {:?}", x),
+            }
+            handle.join().unwrap();
+        }
+
+        fn retrying_client(base_url: String, max_attempts: u32) -> GptClient {
+            let config = GptClientConfig {
+                base_url,
+                retry: Some(RetryPolicy {
+                    max_attempts,
+                    base_delay: Duration::from_millis(1),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            GptClient::with_config("token", config).unwrap()
+        }
+
+        #[tokio::test]
+        async fn client_retries_transient_errors() {
+            let (base_url, handle) = serve(vec![
+                "HTTP/1.1 429 Too Many Requests\r\nretry-after: 0\r\ncontent-length: 0\r\nconnection: close\r\n\r\n".to_string(),
+                json_response("503 Service Unavailable", "busy"),
+                json_response("200 OK", &embedding_body(1.0)),
+            ]);
+            let client = retrying_client(base_url, 3);
+            client.embed("text").await.unwrap();
+            assert_eq!(handle.join().unwrap().len(), 3);
+        }
+
+        #[tokio::test]
+        async fn client_stops_retrying() {
+            let (base_url, handle) = serve(vec![
+                json_response("500 Internal Server Error", "oops"),
+                json_response("500 Internal Server Error", "oops"),
+            ]);
+            let client = retrying_client(base_url, 2);
+            match client.embed("text").await {
+                Err(Error::Api { status: 500, .. }) => {}
+                x => panic!("unexpected result: {:?}", x),
+            }
+            assert_eq!(handle.join().unwrap().len(), 2);
+
+            let (base_url, handle) = serve(vec![json_response("401 Unauthorized", "no")]);
+            let client = retrying_client(base_url, 3);
+            match client.embed("text").await {
+                Err(Error::Unauthorized { .. }) => {}
+                x => panic!("unexpected result: {:?}", x),
+            }
+            assert_eq!(handle.join().unwrap().len(), 1);
+        }
+
+        #[tokio::test]
+        async fn client_embeds_in_batches() {
+            let (base_url, handle) = serve(vec![
+                json_response("200 OK", &embeddings_body(&[(1, 1.0), (0, 0.0)])),
+                json_response("200 OK", &embeddings_body(&[(0, 2.0)])),
+            ]);
+            let config = GptClientConfig {
+                base_url,
+                embedding_batch_size: 2,
+                ..Default::default()
+            };
+            let client = GptClient::with_config("token", config).unwrap();
+            let embeddings = client.embed_batch(&["a", "b", "c"]).await.unwrap();
+            let requests = handle.join().unwrap();
+            assert!(requests[0].ends_with(r#""input":["a","b"]}"#));
+            assert!(requests[1].ends_with(r#""input":["c"]}"#));
+            let firsts: Vec<f32> = embeddings
+                .iter()
+                .map(|x| {
+                    let mut vector = [1.0; EMBED_DIMS];
+                    vector[0] = 0.0;
+                    x.cosine_distance(&GptEmbedding::new("", vector))
+                })
+                .collect();
+            assert!(firsts[0].abs() < 1e-6);
+            assert!(firsts[1] > firsts[0] && firsts[2] > firsts[1]);
+        }
+
+        #[tokio::test]
+        async fn client_sends_configured_request() {
+            let (base_url, handle) = serve(vec![json_response("200 OK", &embedding_body(1.0))]);
+            let mut headers = HeaderMap::new();
+            headers.insert("api-key", HeaderValue::from_static("secret"));
+            let config = GptClientConfig {
+                base_url,
+                organization: Some("org-ait".to_string()),
+                headers,
+                query: vec![("api-version".to_string(), "2023-05-15".to_string())],
+                ..Default::default()
+            };
+            let client = GptClient::with_config("", config).unwrap();
+            client.embed("text").await.unwrap();
+            let request = handle.join().unwrap().remove(0).to_lowercase();
+            assert!(request.starts_with("post /v1/embeddings?api-version=2023-05-15 "));
+            assert!(request.contains("openai-organization: org-ait"));
+            assert!(request.contains("api-key: secret"));
+            assert!(!request.contains("authorization:"));
+        }
+    }
 }
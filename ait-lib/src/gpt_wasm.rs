@@ -0,0 +1,144 @@
+//! Bindings to the `gpt` module for the browser.
+
+use futures_util::StreamExt;
+use js_sys::{Array, Function, Uint8Array};
+use serde::Deserialize;
+use wasm_bindgen::prelude::*;
+
+use crate::embedding;
+use crate::gpt::{
+    self, ChatCompletionMessage, ChatCompletionOptions, CompletionBackend, CompletionOptions,
+    EmbeddingBackend, GptClient, GptClientConfig, RetryPolicy, TextCompletionOptions,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Array contains incorrect values.")]
+    ArrayError,
+    #[error("Callback failed.")]
+    CallbackError,
+    #[error("Options contain incorrect values.")]
+    OptionsError,
+    #[error(transparent)]
+    GptError(#[from] gpt::Error),
+    #[error(transparent)]
+    EmbeddingError(#[from] embedding::Error),
+}
+
+/// Errors are thrown as JS `Error` objects. Errors from the GPT API are named
+/// after their kind (e.g. `RateLimited`) so that callers can tell them apart.
+impl From<Error> for JsValue {
+    fn from(e: Error) -> Self {
+        let error = js_sys::Error::new(&e.to_string());
+        if let Error::GptError(e) = &e {
+            error.set_name(e.kind());
+        }
+        error.into()
+    }
+}
+
+type Result<T> = core::result::Result<T, Error>;
+
+/// Read completion options from a JS object, using the defaults for missing
+/// fields or when `options` is `undefined`.
+fn options_from_js<M>(options: JsValue) -> Result<CompletionOptions<M>>
+where
+    M: Default + for<'de> Deserialize<'de>,
+{
+    serde_wasm_bindgen::from_value::<Option<CompletionOptions<M>>>(options)
+        .map_err(|_| Error::OptionsError)
+        .map(Option::unwrap_or_default)
+}
+
+/// A client which retries rate limited and other transient failures, which
+/// are common with the free tier of the API.
+fn client(token: &str) -> GptClient {
+    let config = GptClientConfig {
+        retry: Some(RetryPolicy::default()),
+        ..Default::default()
+    };
+    GptClient::with_config(token, config).expect("default client config is valid")
+}
+
+#[wasm_bindgen]
+pub async fn text_complete(token: &str, prompt: &str, options: JsValue) -> Result<String> {
+    let options: TextCompletionOptions = options_from_js(options)?;
+    client(token)
+        .text_completion(prompt, &options)
+        .await
+        .map_err(Error::GptError)
+}
+
+/// Read chat messages, as built by `History.build_prompt`, from JS objects.
+fn chat_messages_from_js(messages: Array) -> Result<Vec<ChatCompletionMessage>> {
+    messages
+        .iter()
+        .map(serde_wasm_bindgen::from_value)
+        .map(|x| x.ok())
+        .collect::<Option<Vec<ChatCompletionMessage>>>()
+        .ok_or(Error::ArrayError)
+}
+
+#[wasm_bindgen]
+pub async fn chat_complete(token: &str, messages: Array, options: JsValue) -> Result<String> {
+    let options: ChatCompletionOptions = options_from_js(options)?;
+    client(token)
+        .chat_completion(chat_messages_from_js(messages)?, &options)
+        .await
+        .map_err(Error::GptError)
+}
+
+/// Like `chat_complete`, but calls `on_chunk` with each piece of the response
+/// as it is generated. Resolves to the full response.
+#[wasm_bindgen]
+pub async fn chat_complete_stream(
+    token: &str,
+    messages: Array,
+    options: JsValue,
+    on_chunk: Function,
+) -> Result<String> {
+    let options: ChatCompletionOptions = options_from_js(options)?;
+    let backend = client(token);
+    let chunks = backend
+        .chat_completion_stream(chat_messages_from_js(messages)?, &options)
+        .await
+        .map_err(Error::GptError)?;
+    futures_util::pin_mut!(chunks);
+    let mut response = String::new();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(Error::GptError)?;
+        on_chunk
+            .call1(&JsValue::NULL, &JsValue::from_str(&chunk))
+            .map_err(|_| Error::CallbackError)?;
+        response.push_str(&chunk);
+    }
+    Ok(response)
+}
+
+#[wasm_bindgen]
+pub async fn gpt_embed(token: &str, text: &str) -> Result<Uint8Array> {
+    client(token)
+        .embed(text)
+        .await
+        .map_err(Error::GptError)
+        .and_then(|x| x.serialize().map_err(Error::EmbeddingError))
+        .map(|x| Uint8Array::from(x.as_slice()))
+}
+
+/// Embed each of the `texts`, in as few requests as possible. Resolves to the
+/// serialized embeddings, in the same order as `texts`.
+#[wasm_bindgen]
+pub async fn gpt_embed_batch(token: &str, texts: Vec<String>) -> Result<Array> {
+    let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+    client(token)
+        .embed_batch(&texts)
+        .await
+        .map_err(Error::GptError)?
+        .iter()
+        .map(|x| {
+            x.serialize()
+                .map(|x| Uint8Array::from(x.as_slice()))
+                .map_err(Error::EmbeddingError)
+        })
+        .collect::<Result<Array>>()
+}
@@ -1,11 +1,14 @@
 use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
 use std::cmp::Ordering;
 use std::collections::hash_map::{Entry, HashMap};
 use std::collections::HashSet;
-use wasm_bindgen::prelude::*;
 
 use crate::embedding::Embedding;
-use crate::utils::{new_text_id, TextId};
+use crate::export;
+use crate::hnsw::{Hnsw, HnswParams, Node};
+use crate::store::{self, StoreFormat};
+use crate::utils::{new_text_id, now, TextId, TextIdHex};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -17,24 +20,42 @@ pub enum Error {
     CantBuildExperience,
     #[error("failed to access the experience")]
     CantAccessExperience,
+    #[error("the history already has the experience")]
+    ExperienceExists,
     #[error("failed to parse the embedding")]
     InvalidEmbedding,
     #[error("failed to parse the text ID")]
     InvalidTextId,
-    #[error("failed to build messages")]
-    CantBuildMessages,
+    #[error("failed to build messages: {0}")]
+    CantBuildMessages(String),
+    #[error("failed to parse the options: {0}")]
+    InvalidOptions(String),
+    #[error("the history has format version {version}, which this version of Ait can't read")]
+    UnsupportedVersion { version: u32 },
+    #[error("the history has embeddings with {dims} dimensions, which don't fit this history")]
+    IncompatibleEmbedding { dims: usize },
+    #[error(transparent)]
+    Json(#[from] export::Error),
 }
 
-impl From<Error> for JsValue {
-    fn from(e: Error) -> Self {
-        JsValue::from_str(&e.to_string())
+impl From<store::Error> for Error {
+    fn from(e: store::Error) -> Self {
+        match e {
+            store::Error::UnsupportedVersion { version } => Error::UnsupportedVersion { version },
+            store::Error::IncompatibleEmbedding { dims, .. } => {
+                Error::IncompatibleEmbedding { dims }
+            }
+            _ => Error::CantAccessHistory,
+        }
     }
 }
 
 type Result<T> = core::result::Result<T, Error>;
 
+#[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Experience<const N: usize> {
+    #[serde_as(as = "TextIdHex")]
     pub id: TextId,
     pub embedding: Embedding<N>,
     pub query: String,
@@ -42,17 +63,197 @@ pub struct Experience<const N: usize> {
     pub rank: u32,
 }
 
+#[serde_as]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LinkedExperience<const N: usize> {
     pub experience: Experience<N>,
+    #[serde_as(as = "Vec<TextIdHex>")]
     pub links: Vec<TextId>,
+    /// How often the experience was retrieved as context.
+    #[serde(default)]
+    pub retrievals: u32,
 }
 
+/// Text IDs are serialized as hexadecimal in human readable formats, since
+/// JSON object keys must be strings.
+#[serde_as]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct History<const N: usize> {
+    #[serde_as(as = "HashMap<TextIdHex, _>")]
     experiences: HashMap<TextId, LinkedExperience<N>>,
+    #[serde_as(as = "Option<TextIdHex>")]
     last_id: Option<TextId>,
     next_rank: u32,
+    /// The system prompt template used when querying with this history.
+    #[serde(default)]
+    persona: Option<String>,
+    /// Finds related experiences quickly in large histories, if enabled.
+    #[serde(default)]
+    index: Option<Hnsw>,
+    /// The experiences added, modified or removed since the last call to
+    /// `take_changes`.
+    #[serde(skip)]
+    changes: HashSet<TextId>,
+    /// Kept in the envelope written around the history by `StoreFormat`.
+    #[serde(skip)]
+    embedding_model: Option<String>,
+    #[serde(skip)]
+    created_at: Option<u64>,
+}
+
+/// The fields of a `History` other than its experiences.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryMetadata {
+    #[serde_as(as = "Option<TextIdHex>")]
+    pub last_id: Option<TextId>,
+    pub next_rank: u32,
+    pub persona: Option<String>,
+    #[serde(default)]
+    pub embedding_model: Option<String>,
+    #[serde(default)]
+    pub created_at: Option<u64>,
+    /// The parameters of the index.
+    #[serde(default)]
+    pub index: Option<HnswParams>,
+    /// The node of the index where searches start. The links of each node
+    /// are kept with its experience.
+    #[serde_as(as = "Option<TextIdHex>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index_entry: Option<TextId>,
+}
+
+/// What `History::push_with` does with an exchange already in the history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicatePolicy {
+    /// Fail with `Error::ExperienceExists`.
+    Reject,
+    /// Add the new links to the experience, and leave its rank as it was.
+    #[default]
+    MergeLinks,
+    /// Add the new links to the experience, and rank it as the most recent
+    /// experience.
+    Refresh,
+}
+
+/// The result of `History::push_with`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PushOutcome {
+    pub id: TextId,
+    /// Whether the exchange was new to the history.
+    pub created: bool,
+}
+
+/// How `History::related_with` searches for related experiences.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Search {
+    /// Follow links from the last experience, nearest first. Experiences not
+    /// linked to it, directly or through others, aren't found.
+    #[default]
+    Graph,
+    /// Score every experience.
+    Exhaustive,
+    /// Search the index, which is fast but may miss some of the nearest
+    /// experiences. Without an index, score every experience.
+    Approximate,
+    /// Take the `global` nearest experiences, and fill the rest from the
+    /// graph.
+    Hybrid { global: usize },
+}
+
+/// Options for `History::related_with`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RelatedOptions {
+    pub search: Search,
+    /// Leave out experiences whose cosine distance to the embedding is
+    /// greater than this. The graph isn't searched through them.
+    pub max_distance: Option<f32>,
+    /// Re-rank by maximal marginal relevance, so that near duplicates don't
+    /// crowd out other related experiences. A lambda of 1 ranks by relevance
+    /// alone, and lower values favour diversity.
+    pub mmr_lambda: Option<f32>,
+    /// Rank by a score which also favours recent and often retrieved
+    /// experiences, rather than by distance alone.
+    pub scoring: Option<Scoring>,
+}
+
+/// Scores related experiences by their distance, less bonuses for recency
+/// and retrievals. Lower scores rank first, so that a recent correction can
+/// outrank the stale experience it corrects.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Scoring {
+    /// The recency bonus of the most recent experience. It halves every
+    /// `half_life` experiences added since.
+    pub recency_weight: f32,
+    /// Must be greater than 0.
+    pub half_life: f32,
+    /// The retrieval bonus of an often retrieved experience. An experience
+    /// retrieved `n` times gets `n / (n + 1)` of it.
+    pub retrieval_weight: f32,
+}
+
+impl Default for Scoring {
+    fn default() -> Self {
+        Self {
+            recency_weight: 0.1,
+            half_life: 50.0,
+            retrieval_weight: 0.0,
+        }
+    }
+}
+
+/// The candidates considered when re-ranking, as a multiple of the number of
+/// experiences wanted.
+const RERANK_POOL: usize = 4;
+
+/// An experience found by `History::related`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Related {
+    pub id: TextId,
+    /// The cosine distance from the embedding searched for, from 0 for the
+    /// same direction to 2 for the opposite direction.
+    pub distance: f32,
+}
+
+/// The fraction of the `exact` results which are in `found`, to measure how
+/// many related experiences an approximate search misses.
+pub fn recall(found: &[TextId], exact: &[TextId]) -> f32 {
+    if exact.is_empty() {
+        return 1.0;
+    }
+    let found: HashSet<&TextId> = found.iter().collect();
+    exact.iter().filter(|x| found.contains(x)).count() as f32 / exact.len() as f32
+}
+
+/// Where `History::merge` places the experiences of the other history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergePolicy {
+    /// After this history's experiences, as if they were added since. The
+    /// other history's last experience becomes the last experience.
+    #[default]
+    Append,
+    /// Before this history's experiences, as if they were added earlier. This
+    /// history's last experience remains the last experience.
+    Prepend,
+}
+
+/// The distance between the embeddings of the experiences `a` and `b`, which
+/// is infinite if either isn't in `experiences`.
+fn distance_between<const N: usize>(
+    experiences: &HashMap<TextId, LinkedExperience<N>>,
+    a: &TextId,
+    b: &TextId,
+) -> f32 {
+    match (experiences.get(a), experiences.get(b)) {
+        (Some(a), Some(b)) => a
+            .experience
+            .embedding
+            .cosine_distance(&b.experience.embedding),
+        _ => f32::INFINITY,
+    }
 }
 
 fn insert_sorted_by<T, F>(vec: &mut Vec<T>, item: T, f: F)
@@ -63,19 +264,108 @@ where
     vec.insert(idx, item);
 }
 
+impl<const N: usize> Default for History<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<const N: usize> History<N> {
     pub fn new() -> History<N> {
         History {
             experiences: HashMap::new(),
             last_id: None,
             next_rank: 0,
+            persona: None,
+            index: None,
+            changes: HashSet::new(),
+            embedding_model: None,
+            created_at: Some(now()),
         }
     }
 
+    /// Assemble a history from its `metadata` and `experiences`, as kept by
+    /// a store which keeps them apart. The index is rebuilt.
+    pub fn from_parts(
+        metadata: HistoryMetadata,
+        experiences: impl IntoIterator<Item = LinkedExperience<N>>,
+    ) -> Self {
+        Self::from_records(metadata, experiences.into_iter().map(|x| (x, None)))
+    }
+
+    /// Assemble a history from its `metadata` and its experiences with their
+    /// nodes in the index, as kept by a store with a record per experience.
+    /// The index is rebuilt, and every experience marked as changed, if any
+    /// node is missing.
+    pub fn from_records(
+        metadata: HistoryMetadata,
+        records: impl IntoIterator<Item = (LinkedExperience<N>, Option<Node>)>,
+    ) -> Self {
+        let mut nodes = HashMap::new();
+        let mut history = History {
+            experiences: records
+                .into_iter()
+                .map(|(x, node)| {
+                    let id = x.experience.id;
+                    if let Some(node) = node {
+                        nodes.insert(id, node);
+                    }
+                    (id, x)
+                })
+                .collect(),
+            last_id: metadata.last_id,
+            next_rank: metadata.next_rank,
+            persona: metadata.persona,
+            index: None,
+            changes: HashSet::new(),
+            embedding_model: metadata.embedding_model,
+            created_at: metadata.created_at,
+        };
+        match metadata.index {
+            Some(params) if nodes.len() == history.experiences.len() => {
+                history.index = Some(Hnsw::from_nodes(params, nodes, metadata.index_entry));
+            }
+            Some(params) => history.set_index(Some(params)),
+            None => {}
+        }
+        history
+    }
+
+    pub fn metadata(&self) -> HistoryMetadata {
+        HistoryMetadata {
+            last_id: self.last_id,
+            next_rank: self.next_rank,
+            persona: self.persona.clone(),
+            embedding_model: self.embedding_model.clone(),
+            created_at: self.created_at,
+            index: self.index.as_ref().map(Hnsw::params),
+            index_entry: self.index.as_ref().and_then(Hnsw::entry),
+        }
+    }
+
+    /// The node of the experience `text_id` in the index, to be stored with
+    /// the experience.
+    pub fn index_node(&self, text_id: &TextId) -> Option<&Node> {
+        self.index.as_ref()?.node(text_id)
+    }
+
+    /// The experiences added, modified or removed since the last call, so
+    /// that a store can write only those.
+    pub fn take_changes(&mut self) -> Vec<TextId> {
+        self.changes.drain().collect()
+    }
+
     pub fn len(&self) -> usize {
         self.experiences.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.experiences.is_empty()
+    }
+
+    /// Add an exchange to the history, and return its ID. An exchange already
+    /// in the history gains the new `links`, as with
+    /// `DuplicatePolicy::MergeLinks`.
     pub fn push(
         &mut self,
         query: &str,
@@ -83,78 +373,553 @@ impl<const N: usize> History<N> {
         embedding: Embedding<N>,
         links: Vec<TextId>,
     ) -> Result<TextId> {
+        self.push_with(
+            query,
+            response,
+            embedding,
+            links,
+            DuplicatePolicy::default(),
+        )
+        .map(|x| x.id)
+    }
+
+    /// Add an exchange to the history, handling an exchange already in the
+    /// history according to `policy`.
+    pub fn push_with(
+        &mut self,
+        query: &str,
+        response: &str,
+        embedding: Embedding<N>,
+        links: Vec<TextId>,
+        policy: DuplicatePolicy,
+    ) -> Result<PushOutcome> {
         let id = new_text_id(&[query, response]);
-        if let Entry::Vacant(entry) = self.experiences.entry(id.clone()) {
-            entry.insert(LinkedExperience::<N> {
-                experience: Experience::<N> {
+        let existing = match self.experiences.get_mut(&id) {
+            Some(existing) => existing,
+            None => {
+                self.experiences.insert(
                     id,
-                    query: query.to_string(),
-                    response: response.to_string(),
-                    embedding,
-                    rank: self.next_rank,
-                },
-                links,
-            });
+                    LinkedExperience::<N> {
+                        experience: Experience::<N> {
+                            id,
+                            query: query.to_string(),
+                            response: response.to_string(),
+                            embedding,
+                            rank: self.next_rank,
+                        },
+                        links,
+                        retrievals: 0,
+                    },
+                );
+                self.index_insert(id);
+                self.changes.insert(id);
+                self.last_id = Some(id);
+                self.next_rank += 1;
+                return Ok(PushOutcome { id, created: true });
+            }
         };
-        self.last_id = Some(id.clone());
-        self.next_rank += 1;
-        Ok(id.clone())
+        if policy == DuplicatePolicy::Reject {
+            return Err(Error::ExperienceExists);
+        }
+        for link in links {
+            if link != id && !existing.links.contains(&link) {
+                existing.links.push(link);
+                self.changes.insert(id);
+            }
+        }
+        if policy == DuplicatePolicy::Refresh {
+            existing.experience.rank = self.next_rank;
+            self.changes.insert(id);
+            self.last_id = Some(id);
+            self.next_rank += 1;
+        }
+        Ok(PushOutcome { id, created: false })
+    }
+
+    pub fn persona(&self) -> Option<&str> {
+        self.persona.as_deref()
+    }
+
+    pub fn set_persona(&mut self, persona: Option<String>) {
+        self.persona = persona;
     }
 
     pub fn get(&self, text_id: &TextId) -> Option<&Experience<N>> {
         self.experiences.get(text_id).map(|x| &x.experience)
     }
 
-    pub fn related(&self, embedding: &Embedding<N>, num: usize) -> Result<Vec<TextId>> {
-        let mut related: Vec<(f32, TextId)> = Vec::new();
+    /// The experience `text_id` along with its links.
+    pub fn linked(&self, text_id: &TextId) -> Option<&LinkedExperience<N>> {
+        self.experiences.get(text_id)
+    }
+
+    /// The experiences linked from the experience `text_id`.
+    pub fn links(&self, text_id: &TextId) -> Option<&[TextId]> {
+        self.experiences.get(text_id).map(|x| x.links.as_slice())
+    }
+
+    /// How often the experience `text_id` was retrieved as context.
+    pub fn retrievals(&self, text_id: &TextId) -> Option<u32> {
+        self.experiences.get(text_id).map(|x| x.retrievals)
+    }
+
+    /// Count a retrieval of each of the experiences `text_ids`, as when they
+    /// are given as context. IDs not in the history are ignored.
+    pub fn record_retrieval(&mut self, text_ids: &[TextId]) {
+        for id in text_ids {
+            if let Some(experience) = self.experiences.get_mut(id) {
+                experience.retrievals = experience.retrievals.saturating_add(1);
+                self.changes.insert(*id);
+            }
+        }
+    }
+
+    /// The experiences, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &Experience<N>> {
+        self.experiences.values().map(|x| &x.experience)
+    }
+
+    /// Remove the experience `text_id` and return it. Experiences which
+    /// linked to it link to its links instead, so that traversal still
+    /// reaches them.
+    pub fn remove(&mut self, text_id: &TextId) -> Option<Experience<N>> {
+        let removed = self.experiences.remove(text_id)?;
+        self.index_remove(text_id);
+        self.changes.insert(*text_id);
+        for experience in self.experiences.values_mut() {
+            let Some(idx) = experience.links.iter().position(|x| x == text_id) else {
+                continue;
+            };
+            experience.links.remove(idx);
+            for link in &removed.links {
+                if *link != experience.experience.id && !experience.links.contains(link) {
+                    experience.links.push(*link);
+                }
+            }
+            self.changes.insert(experience.experience.id);
+        }
+        if self.last_id.as_ref() == Some(text_id) {
+            self.last_id = self
+                .experiences
+                .values()
+                .max_by_key(|x| x.experience.rank)
+                .map(|x| x.experience.id);
+        }
+        Some(removed.experience)
+    }
+
+    /// Replace the response of the experience `text_id`, and return its new
+    /// ID. The experience keeps its rank and links, and links to it are
+    /// rewritten to the new ID.
+    pub fn update_response(
+        &mut self,
+        text_id: &TextId,
+        response: &str,
+        embedding: Embedding<N>,
+    ) -> Result<TextId> {
+        let query = &self
+            .experiences
+            .get(text_id)
+            .ok_or(Error::CantAccessExperience)?
+            .experience
+            .query;
+        let id = new_text_id(&[query, response]);
+        if id != *text_id && self.experiences.contains_key(&id) {
+            return Err(Error::ExperienceExists);
+        }
+        let mut linked = self.experiences.remove(text_id).unwrap();
+        linked.experience.id = id;
+        linked.experience.response = response.to_string();
+        linked.experience.embedding = embedding;
+        self.experiences.insert(id, linked);
+        self.index_remove(text_id);
+        self.index_insert(id);
+        self.changes.insert(*text_id);
+        self.changes.insert(id);
+        for experience in self.experiences.values_mut() {
+            for link in experience.links.iter_mut().filter(|x| *x == text_id) {
+                *link = id;
+                self.changes.insert(experience.experience.id);
+            }
+        }
+        if self.last_id.as_ref() == Some(text_id) {
+            self.last_id = Some(id);
+        }
+        Ok(id)
+    }
+
+    /// Add the experiences of `other` to this history, and return how many
+    /// were new. An experience in both histories is kept once, at its earliest
+    /// position, with the links and retrievals of both. Ranks are renumbered so that each
+    /// history's experiences keep their order.
+    pub fn merge(&mut self, other: History<N>, policy: MergePolicy) -> usize {
+        let before: HashMap<TextId, (u32, usize, u32)> = self
+            .experiences
+            .values()
+            .map(|x| {
+                let state = (x.experience.rank, x.links.len(), x.retrievals);
+                (x.experience.id, state)
+            })
+            .collect();
+        let by_rank = |experiences: HashMap<TextId, LinkedExperience<N>>| {
+            let mut experiences: Vec<_> = experiences.into_values().collect();
+            experiences.sort_by_key(|x| x.experience.rank);
+            experiences
+        };
+        let ours = by_rank(std::mem::take(&mut self.experiences));
+        let theirs = by_rank(other.experiences);
+        let (first, second, last_id) = match policy {
+            MergePolicy::Append => (ours, theirs, other.last_id.or(self.last_id)),
+            MergePolicy::Prepend => (theirs, ours, self.last_id.or(other.last_id)),
+        };
+
+        let mut order = Vec::new();
+        for experience in first.into_iter().chain(second) {
+            match self.experiences.entry(experience.experience.id) {
+                Entry::Occupied(mut entry) => {
+                    let existing = entry.get_mut();
+                    for link in experience.links {
+                        if !existing.links.contains(&link) {
+                            existing.links.push(link);
+                        }
+                    }
+                    existing.retrievals = existing.retrievals.saturating_add(experience.retrievals);
+                }
+                Entry::Vacant(entry) => {
+                    order.push(experience.experience.id);
+                    entry.insert(experience);
+                }
+            }
+        }
+        for (rank, id) in order.iter().enumerate() {
+            let experience = self.experiences.get_mut(id).unwrap();
+            experience.experience.rank = rank as u32;
+            let state = (rank as u32, experience.links.len(), experience.retrievals);
+            if before.get(id) != Some(&state) {
+                self.changes.insert(*id);
+            }
+        }
+
+        self.last_id = last_id;
+        self.next_rank = order.len() as u32;
+        if self.persona.is_none() {
+            self.persona = other.persona;
+        }
+        if self.embedding_model.is_none() {
+            self.embedding_model = other.embedding_model;
+        }
+        // the other history's experiences aren't in the index
+        if let Some(params) = self.index.as_ref().map(Hnsw::params) {
+            self.set_index(Some(params));
+        }
+        order.iter().filter(|x| !before.contains_key(*x)).count()
+    }
+
+    /// Index the experiences with `params`, so that related experiences are
+    /// found without scoring every experience, or remove the index if `None`.
+    /// The index is kept in sync as experiences are added and removed.
+    pub fn set_index(&mut self, params: Option<HnswParams>) {
+        // the node of every experience changes
+        if self.index.is_some() || params.is_some() {
+            self.changes.extend(self.experiences.keys().copied());
+        }
+        self.index = params.map(Hnsw::new);
+        // adding in order of rank builds the same index however the history
+        // was loaded
+        let mut ids: Vec<(u32, TextId)> = self
+            .experiences
+            .values()
+            .map(|x| (x.experience.rank, x.experience.id))
+            .collect();
+        ids.sort();
+        for (_, id) in ids {
+            self.index_insert(id);
+        }
+    }
+
+    pub fn index(&self) -> Option<&Hnsw> {
+        self.index.as_ref()
+    }
+
+    /// Change the candidates the index considers when searching.
+    pub fn set_ef_search(&mut self, ef_search: usize) {
+        if let Some(index) = &mut self.index {
+            index.set_ef_search(ef_search);
+        }
+    }
+
+    /// The recall of the index for the `num` experiences nearest to
+    /// `embedding`, or `None` if there is no index.
+    pub fn index_recall(&self, embedding: &Embedding<N>, num: usize) -> Option<f32> {
+        let index = self.index.as_ref()?;
+        let found: Vec<TextId> = index
+            .search(num, &|x| self.distance_to(embedding, x))
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        let exact: Vec<TextId> = self.nearest(embedding, num).iter().map(|x| x.id).collect();
+        Some(recall(&found, &exact))
+    }
+
+    fn distance_to(&self, embedding: &Embedding<N>, id: &TextId) -> f32 {
+        self.experiences.get(id).map_or(f32::INFINITY, |x| {
+            x.experience.embedding.cosine_distance(embedding)
+        })
+    }
+
+    fn index_insert(&mut self, id: TextId) {
+        let experiences = &self.experiences;
+        if let Some(index) = &mut self.index {
+            let changed = index.insert(id, &|a, b| distance_between(experiences, a, b));
+            self.changes.extend(changed);
+        }
+    }
+
+    fn index_remove(&mut self, id: &TextId) {
+        let experiences = &self.experiences;
+        if let Some(index) = &mut self.index {
+            let changed = index.remove(id, &|a, b| distance_between(experiences, a, b));
+            self.changes.extend(changed);
+        }
+    }
+
+    /// The model which made the embeddings, if it is known.
+    pub fn embedding_model(&self) -> Option<&str> {
+        self.embedding_model.as_deref()
+    }
+
+    pub fn set_embedding_model(&mut self, model: Option<String>) {
+        self.embedding_model = model;
+    }
+
+    /// When the history was created, in seconds since the Unix epoch. It isn't
+    /// known for histories stored before it was recorded.
+    pub fn created_at(&self) -> Option<u64> {
+        self.created_at
+    }
+
+    pub fn set_created_at(&mut self, created_at: Option<u64>) {
+        self.created_at = created_at;
+    }
+
+    /// Serialize as MessagePack, in the versioned format of `StoreFormat`.
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        StoreFormat::MessagePack
+            .serialize(self)
+            .map_err(|_| Error::CantStoreHistory)
+    }
+
+    /// Deserialize from MessagePack, upgrading histories stored in an older
+    /// format.
+    pub fn deserialize(data: &impl AsRef<[u8]>) -> Result<Self> {
+        Ok(StoreFormat::MessagePack.deserialize(data.as_ref())?)
+    }
+
+    /// The experiences most related to `embedding`, most related first, found
+    /// by following links from the last experience.
+    pub fn related(&self, embedding: &Embedding<N>, num: usize) -> Result<Vec<Related>> {
+        self.related_with(embedding, num, &RelatedOptions::default())
+    }
+
+    /// The experiences most related to `embedding`, most related first, found
+    /// as set by `options`.
+    pub fn related_with(
+        &self,
+        embedding: &Embedding<N>,
+        num: usize,
+        options: &RelatedOptions,
+    ) -> Result<Vec<Related>> {
+        if let Some(scoring) = &options.scoring {
+            if scoring.half_life.is_nan() || scoring.half_life <= 0.0 {
+                return Err(Error::InvalidOptions(format!(
+                    "the half life must be greater than 0, not {}",
+                    scoring.half_life
+                )));
+            }
+        }
+        if options.mmr_lambda.is_none() && options.scoring.is_none() {
+            return Ok(self.search(embedding, num, options));
+        }
+        let candidates = self.search(embedding, num.saturating_mul(RERANK_POOL), options);
+        let mut candidates: Vec<(f32, Related)> = candidates
+            .into_iter()
+            .map(|x| match &options.scoring {
+                Some(scoring) => (self.score(&x, scoring), x),
+                None => (x.distance, x),
+            })
+            .collect();
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(match options.mmr_lambda {
+            Some(lambda) => self.diversify(candidates, num, lambda),
+            None => candidates.into_iter().take(num).map(|x| x.1).collect(),
+        })
+    }
+
+    /// The score of a related experience, as set by `scoring`.
+    fn score(&self, related: &Related, scoring: &Scoring) -> f32 {
+        let linked = match self.experiences.get(&related.id) {
+            Some(linked) => linked,
+            None => return related.distance,
+        };
+        let age = self.next_rank.saturating_sub(linked.experience.rank + 1) as f32;
+        let recency = 0.5f32.powf(age / scoring.half_life);
+        let retrievals = linked.retrievals as f32 / (linked.retrievals as f32 + 1.0);
+        related.distance - scoring.recency_weight * recency - scoring.retrieval_weight * retrievals
+    }
+
+    fn search(
+        &self,
+        embedding: &Embedding<N>,
+        num: usize,
+        options: &RelatedOptions,
+    ) -> Vec<Related> {
+        let max_distance = options.max_distance.unwrap_or(f32::INFINITY);
+        let mut related = match options.search {
+            Search::Graph => return self.traverse(embedding, num, max_distance),
+            Search::Exhaustive => self.nearest(embedding, num),
+            Search::Approximate => match &self.index {
+                Some(index) => index
+                    .search(num, &|x| self.distance_to(embedding, x))
+                    .into_iter()
+                    .map(|(id, distance)| Related { id, distance })
+                    .collect(),
+                None => self.nearest(embedding, num),
+            },
+            Search::Hybrid { global } => {
+                // distant experiences are cut off before the graph fills the
+                // rest, so that they don't take its places
+                let mut related = self.nearest(embedding, global.min(num));
+                related.retain(|x| x.distance <= max_distance);
+                let traversed =
+                    self.traverse(embedding, num.saturating_add(related.len()), max_distance);
+                for x in traversed {
+                    if related.len() >= num {
+                        break;
+                    }
+                    if !related.iter().any(|y| y.id == x.id) {
+                        related.push(x);
+                    }
+                }
+                related.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+                related
+            }
+        };
+        related.retain(|x| x.distance <= max_distance);
+        related
+    }
+
+    /// Pick `num` of the scored `candidates` by maximal marginal relevance:
+    /// each pick is the candidate most relevant to the embedding, less its
+    /// similarity to the candidates already picked, weighted by `lambda` and
+    /// `1 - lambda`. Relevance is one less the score.
+    fn diversify(
+        &self,
+        mut candidates: Vec<(f32, Related)>,
+        num: usize,
+        lambda: f32,
+    ) -> Vec<Related> {
+        let mut picked: Vec<Related> = Vec::new();
+        // the greatest similarity of each candidate to a picked candidate
+        let mut redundancy = vec![0.0f32; candidates.len()];
+        while picked.len() < num && !candidates.is_empty() {
+            let score = |i: usize| {
+                let redundancy = if picked.is_empty() {
+                    0.0
+                } else {
+                    redundancy[i]
+                };
+                lambda * (1.0 - candidates[i].0) - (1.0 - lambda) * redundancy
+            };
+            // candidates are best scored first, so ties go to the best scored
+            let best = (0..candidates.len())
+                .rev()
+                .max_by(|a, b| score(*a).total_cmp(&score(*b)))
+                .unwrap();
+            let (_, pick) = candidates.remove(best);
+            redundancy.remove(best);
+            for ((_, candidate), redundancy) in candidates.iter().zip(redundancy.iter_mut()) {
+                let similarity = 1.0 - distance_between(&self.experiences, &candidate.id, &pick.id);
+                *redundancy = if picked.is_empty() {
+                    similarity
+                } else {
+                    redundancy.max(similarity)
+                };
+            }
+            picked.push(pick);
+        }
+        picked
+    }
+
+    /// The `num` experiences nearest to `embedding`, nearest first, found by
+    /// scoring every experience.
+    pub fn nearest(&self, embedding: &Embedding<N>, num: usize) -> Vec<Related> {
+        let mut nearest: Vec<Related> = self
+            .experiences
+            .values()
+            .map(|x| Related {
+                id: x.experience.id,
+                distance: x.experience.embedding.cosine_distance(embedding),
+            })
+            .collect();
+        nearest.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        nearest.truncate(num);
+        nearest
+    }
+
+    /// Follow links from the last experience, nearest first. Experiences
+    /// further than `max_distance` aren't followed.
+    fn traverse(&self, embedding: &Embedding<N>, num: usize, max_distance: f32) -> Vec<Related> {
+        let mut related: Vec<Related> = Vec::new();
         let mut added: HashSet<&TextId> = HashSet::new();
-        let mut queue: Vec<(f32, TextId)> = self
+        let mut queue: Vec<Related> = self
             .last_id
             .as_ref()
             .and_then(|x| self.experiences.get(x))
-            .map(|x| {
-                vec![(
-                    x.experience.embedding.cosine_distance(&embedding),
-                    x.experience.id,
-                )]
+            .map(|x| Related {
+                id: x.experience.id,
+                distance: x.experience.embedding.cosine_distance(embedding),
             })
-            .unwrap_or_else(|| Vec::new());
-        loop {
-            if related.len() >= num {
-                break;
-            }
-            if let Some((distance, next_id)) = queue.pop() {
-                let experience = self.experiences.get(&next_id);
-                let LinkedExperience { links, .. } = match experience {
+            .filter(|x| x.distance <= max_distance)
+            .into_iter()
+            .collect();
+        added.extend(self.last_id.iter());
+        while related.len() < num {
+            let next = match queue.pop() {
+                Some(next) => next,
+                None => break,
+            };
+            let links = match self.experiences.get(&next.id) {
+                Some(LinkedExperience { links, .. }) => links,
+                None => continue,
+            };
+            // push most related to front of results
+            insert_sorted_by(&mut related, next, |x| x.distance.total_cmp(&next.distance));
+            for link_id in links {
+                if added.contains(link_id) {
+                    continue;
+                }
+                let experience = self.experiences.get(link_id);
+                let LinkedExperience { experience, .. } = match experience {
                     Some(experience) => experience,
                     None => continue,
                 };
-                // push most related to front of results
-                insert_sorted_by(&mut related, (distance, next_id.clone()), |(x, _)| {
-                    x.total_cmp(&distance)
-                });
-                for link_id in links {
-                    if added.contains(link_id) {
-                        continue;
-                    }
-                    let experience = self.experiences.get(link_id);
-                    let LinkedExperience { experience, .. } = match experience {
-                        Some(experience) => experience,
-                        None => continue,
-                    };
-                    let distance = experience.embedding.cosine_distance(&embedding);
-                    added.insert(link_id);
-                    // push most related to back so they are prioritized
-                    insert_sorted_by(&mut queue, (distance, link_id.clone()), |(x, _)| {
-                        x.total_cmp(&distance).reverse()
-                    });
+                let distance = experience.embedding.cosine_distance(embedding);
+                added.insert(link_id);
+                if distance > max_distance {
+                    continue;
                 }
-            } else {
-                break;
+                // push most related to back so they are prioritized
+                insert_sorted_by(
+                    &mut queue,
+                    Related {
+                        id: *link_id,
+                        distance,
+                    },
+                    |x| x.distance.total_cmp(&distance).reverse(),
+                );
             }
         }
-        let related: Vec<TextId> = related.into_iter().map(|(_, x)| x).collect();
-        Ok(related)
+        related
     }
 }
 
@@ -162,6 +927,10 @@ impl<const N: usize> History<N> {
 mod test {
     use super::*;
 
+    fn related_ids(related: &[Related]) -> Vec<TextId> {
+        related.iter().map(|x| x.id).collect()
+    }
+
     #[test]
     fn history_pushes_and_gets() {
         let mut history = History::<2>::new();
@@ -174,6 +943,507 @@ mod test {
     }
 
     #[test]
+    fn history_rejects_duplicates() {
+        let mut history = History::<2>::new();
+        let id1 = history
+            .push("q1", "r1", Embedding::new("", [0.0, 1.0]), vec![])
+            .unwrap();
+        history
+            .push("q2", "r2", Embedding::new("", [1.0, 0.0]), vec![])
+            .unwrap();
+        history.take_changes();
+        let result = history.push_with(
+            "q1",
+            "r1",
+            Embedding::new("", [0.0, 1.0]),
+            vec![],
+            DuplicatePolicy::Reject,
+        );
+        assert!(matches!(result, Err(Error::ExperienceExists)));
+        assert!(history.take_changes().is_empty());
+        assert_eq!(history.get(&id1).unwrap().rank, 0);
+    }
+
+    #[test]
+    fn history_merges_duplicate_links() {
+        let mut history = History::<2>::new();
+        let id1 = history
+            .push("q1", "r1", Embedding::new("", [0.0, 1.0]), vec![])
+            .unwrap();
+        let id2 = history
+            .push("q2", "r2", Embedding::new("", [1.0, 0.0]), vec![])
+            .unwrap();
+        history.take_changes();
+        let outcome = history
+            .push_with(
+                "q1",
+                "r1",
+                Embedding::new("", [0.0, 1.0]),
+                vec![id2, id1],
+                DuplicatePolicy::MergeLinks,
+            )
+            .unwrap();
+        assert_eq!(
+            outcome,
+            PushOutcome {
+                id: id1,
+                created: false
+            }
+        );
+        assert_eq!(history.links(&id1).unwrap(), [id2]);
+        assert_eq!(history.get(&id1).unwrap().rank, 0);
+        assert_eq!(history.metadata().last_id, Some(id2));
+        assert_eq!(history.metadata().next_rank, 2);
+        assert_eq!(history.take_changes(), vec![id1]);
+    }
+
+    #[test]
+    fn history_refreshes_duplicates() {
+        let mut history = History::<2>::new();
+        let id1 = history
+            .push("q1", "r1", Embedding::new("", [0.0, 1.0]), vec![])
+            .unwrap();
+        history
+            .push("q2", "r2", Embedding::new("", [1.0, 0.0]), vec![])
+            .unwrap();
+        history.take_changes();
+        let outcome = history
+            .push_with(
+                "q1",
+                "r1",
+                Embedding::new("", [0.0, 1.0]),
+                vec![],
+                DuplicatePolicy::Refresh,
+            )
+            .unwrap();
+        assert!(!outcome.created);
+        assert_eq!(history.get(&id1).unwrap().rank, 2);
+        assert_eq!(history.metadata().last_id, Some(id1));
+        assert_eq!(history.metadata().next_rank, 3);
+        assert_eq!(history.take_changes(), vec![id1]);
+        let outcome = history
+            .push_with(
+                "q3",
+                "r3",
+                Embedding::new("", [1.0, 1.0]),
+                vec![],
+                DuplicatePolicy::Refresh,
+            )
+            .unwrap();
+        assert!(outcome.created);
+    }
+
+    #[test]
+    fn history_repairs_links() {
+        let mut history = History::<2>::new();
+        let id1 = history
+            .push("q1", "r1", Embedding::new("", [0.0, 1.0]), vec![])
+            .unwrap();
+        let id2 = history
+            .push("q2", "r2", Embedding::new("", [1.0, 0.0]), vec![id1])
+            .unwrap();
+        let id3 = history
+            .push("q3", "r3", Embedding::new("", [1.0, 1.0]), vec![id2])
+            .unwrap();
+
+        let new_id1 = history
+            .update_response(&id1, "r1'", Embedding::new("", [0.0, 0.5]))
+            .unwrap();
+        assert!(history.get(&id1).is_none());
+        assert_eq!(history.get(&new_id1).unwrap().response, "r1'");
+        assert_eq!(history.get(&new_id1).unwrap().rank, 0);
+        assert_eq!(history.links(&id2).unwrap(), [new_id1]);
+        assert!(matches!(
+            history.update_response(&new_id1, "r1'", Embedding::new("", [0.0, 0.5])),
+            Ok(x) if x == new_id1
+        ));
+
+        history.take_changes();
+        history.remove(&id2);
+        assert_eq!(history.links(&id3).unwrap(), [new_id1]);
+        let mut changes = history.take_changes();
+        changes.sort();
+        let mut expected = vec![id2, id3];
+        expected.sort();
+        assert_eq!(changes, expected);
+
+        history.remove(&id3);
+        assert_eq!(history.metadata().last_id, Some(new_id1));
+    }
+
+    #[test]
+    fn related_options_read_partial_objects() {
+        let options: RelatedOptions = serde_json::from_str(
+            r#"{"search": {"hybrid": {"global": 4}}, "scoring": {"half_life": 10}}"#,
+        )
+        .unwrap();
+        assert_eq!(options.search, Search::Hybrid { global: 4 });
+        assert_eq!(options.mmr_lambda, None);
+        assert_eq!(options.scoring.unwrap().half_life, 10.0);
+        let options: RelatedOptions = serde_json::from_str(r#"{"search": "exhaustive"}"#).unwrap();
+        assert_eq!(options.search, Search::Exhaustive);
+    }
+
+    #[test]
+    fn history_finds_unlinked_experiences() {
+        let mut history = History::<2>::new();
+        let id1 = history
+            .push("q1", "r1", Embedding::new("", [0.0, 1.0]), vec![])
+            .unwrap();
+        let id2 = history
+            .push("q2", "r2", Embedding::new("", [1.0, 0.2]), vec![])
+            .unwrap();
+        let id3 = history
+            .push("q3", "r3", Embedding::new("", [1.0, 0.0]), vec![])
+            .unwrap();
+        let embedding = Embedding::new("", [0.0, 1.0]);
+
+        // only the last experience is reached through the graph
+        let graph = history.related(&embedding, 2).unwrap();
+        assert_eq!(related_ids(&graph), [id3]);
+        let exact = history.nearest(&embedding, 2);
+        assert_eq!(related_ids(&exact), [id1, id2]);
+        assert_eq!(recall(&related_ids(&graph), &related_ids(&exact)), 0.0);
+
+        let options = RelatedOptions {
+            search: Search::Hybrid { global: 1 },
+            ..Default::default()
+        };
+        let hybrid = history.related_with(&embedding, 2, &options).unwrap();
+        assert_eq!(related_ids(&hybrid), [id1, id3]);
+        assert_eq!(recall(&related_ids(&hybrid), &related_ids(&exact)), 0.5);
+        let hybrid = history
+            .related_with(&embedding, usize::MAX, &options)
+            .unwrap();
+        assert_eq!(related_ids(&hybrid), [id1, id3]);
+    }
+
+    #[test]
+    fn history_cuts_off_distant_experiences() {
+        let mut history = History::<2>::new();
+        let id1 = history
+            .push("q1", "r1", Embedding::new("", [0.0, 1.0]), vec![])
+            .unwrap();
+        let id2 = history
+            .push("q2", "r2", Embedding::new("", [1.0, 1.0]), vec![id1])
+            .unwrap();
+        let id3 = history
+            .push("q3", "r3", Embedding::new("", [1.0, 0.0]), vec![id2])
+            .unwrap();
+        let embedding = Embedding::new("", [1.0, 0.1]);
+
+        let related = history.related(&embedding, 3).unwrap();
+        assert_eq!(related_ids(&related), [id3, id2, id1]);
+        assert!(related[0].distance < related[1].distance);
+        assert!(
+            (related[2].distance - embedding.cosine_distance(&Embedding::new("", [0.0, 1.0])))
+                .abs()
+                < 1e-6
+        );
+
+        let options = RelatedOptions {
+            max_distance: Some(0.5),
+            ..Default::default()
+        };
+        let related = history.related_with(&embedding, 3, &options).unwrap();
+        assert_eq!(related_ids(&related), [id3, id2]);
+        // the graph isn't searched through distant experiences
+        let options = RelatedOptions {
+            max_distance: Some(0.1),
+            ..Default::default()
+        };
+        let related = history.related_with(&embedding, 3, &options).unwrap();
+        assert_eq!(related_ids(&related), [id3]);
+        let options = RelatedOptions {
+            search: Search::Exhaustive,
+            max_distance: Some(0.5),
+            ..Default::default()
+        };
+        let related = history.related_with(&embedding, 3, &options).unwrap();
+        assert_eq!(related_ids(&related), [id3, id2]);
+        let options = RelatedOptions {
+            search: Search::Hybrid { global: 1 },
+            max_distance: Some(0.5),
+            ..Default::default()
+        };
+        let related = history.related_with(&embedding, 3, &options).unwrap();
+        assert_eq!(related_ids(&related), [id3, id2]);
+    }
+
+    #[test]
+    fn history_diversifies_related_experiences() {
+        let mut history = History::<2>::new();
+        let id1 = history
+            .push("q1", "r1", Embedding::new("", [1.0, 0.0]), vec![])
+            .unwrap();
+        let id2 = history
+            .push("q2", "r2", Embedding::new("", [1.0, 0.01]), vec![])
+            .unwrap();
+        let id3 = history
+            .push("q3", "r3", Embedding::new("", [0.7, 0.7]), vec![])
+            .unwrap();
+        let embedding = Embedding::new("", [1.0, 0.05]);
+
+        let mut options = RelatedOptions {
+            search: Search::Exhaustive,
+            ..Default::default()
+        };
+        let related = history.related_with(&embedding, 2, &options).unwrap();
+        assert_eq!(related_ids(&related), [id2, id1]);
+        // the near duplicate is passed over
+        options.mmr_lambda = Some(0.5);
+        let related = history.related_with(&embedding, 2, &options).unwrap();
+        assert_eq!(related_ids(&related), [id2, id3]);
+        options.mmr_lambda = Some(1.0);
+        let related = history.related_with(&embedding, 2, &options).unwrap();
+        assert_eq!(related_ids(&related), [id2, id1]);
+    }
+
+    #[test]
+    fn history_scores_recent_experiences() {
+        let mut history = History::<2>::new();
+        let stale = history
+            .push("q1", "r1", Embedding::new("", [1.0, 0.0]), vec![])
+            .unwrap();
+        let correction = history
+            .push("q2", "r2", Embedding::new("", [0.95, 0.3]), vec![stale])
+            .unwrap();
+        let embedding = Embedding::new("", [1.0, 0.0]);
+
+        let mut options = RelatedOptions {
+            search: Search::Exhaustive,
+            ..Default::default()
+        };
+        let related = history.related_with(&embedding, 2, &options).unwrap();
+        assert_eq!(related_ids(&related), [stale, correction]);
+        options.scoring = Some(Scoring {
+            recency_weight: 0.1,
+            half_life: 0.5,
+            retrieval_weight: 0.1,
+        });
+        let related = history.related_with(&embedding, 2, &options).unwrap();
+        assert_eq!(related_ids(&related), [correction, stale]);
+        // distances aren't changed by the score
+        assert!(related[0].distance > related[1].distance);
+
+        history.take_changes();
+        history.record_retrieval(&[stale, stale, stale]);
+        assert_eq!(history.retrievals(&stale), Some(3));
+        assert_eq!(history.take_changes(), [stale]);
+        let related = history.related_with(&embedding, 2, &options).unwrap();
+        assert_eq!(related_ids(&related), [stale, correction]);
+
+        for half_life in [0.0, -1.0, f32::NAN] {
+            options.scoring = Some(Scoring {
+                half_life,
+                ..Default::default()
+            });
+            assert!(matches!(
+                history.related_with(&embedding, 2, &options),
+                Err(Error::InvalidOptions(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn history_indexes_experiences() {
+        // a linear congruential generator, so that the test is repeatable
+        let mut seed = 1u64;
+        let mut vector = || {
+            [0; 8].map(|_| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (seed >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+            })
+        };
+        let mut history = History::<8>::new();
+        let mut ids = Vec::new();
+        for i in 0..300 {
+            let embedding = Embedding::new("", vector());
+            ids.push(history.push(&i.to_string(), "", embedding, vec![]).unwrap());
+        }
+        history.set_index(Some(HnswParams {
+            m: 8,
+            ef_construction: 40,
+            ef_search: 40,
+        }));
+        for id in &ids[..50] {
+            history.remove(id);
+        }
+        let new_id = history
+            .update_response(&ids[50], "updated", Embedding::new("", vector()))
+            .unwrap();
+        assert_eq!(history.index().unwrap().len(), history.len());
+        assert!(history.index().unwrap().contains(&new_id));
+
+        let queries: Vec<_> = (0..20).map(|_| Embedding::new("", vector())).collect();
+        let recall = queries
+            .iter()
+            .map(|x| history.index_recall(x, 10).unwrap())
+            .sum::<f32>()
+            / queries.len() as f32;
+        assert!(recall > 0.9, "recall {}", recall);
+        let options = RelatedOptions {
+            search: Search::Approximate,
+            ..Default::default()
+        };
+        let related = history.related_with(&queries[0], 10, &options).unwrap();
+        assert_eq!(related.len(), 10);
+
+        // a push changes the nodes of only the experiences it is linked to
+        history.take_changes();
+        let id = history
+            .push("new", "", Embedding::new("", vector()), vec![])
+            .unwrap();
+        let changes = history.take_changes();
+        assert!(changes.contains(&id));
+        assert!(
+            changes.len() < history.len() / 4,
+            "{} changes",
+            changes.len()
+        );
+
+        // the index is stored with the history, and with its records
+        let stored = History::<8>::deserialize(&history.serialize().unwrap()).unwrap();
+        let related = history.related_with(&queries[0], 10, &options).unwrap();
+        assert_eq!(
+            stored.related_with(&queries[0], 10, &options).unwrap(),
+            related
+        );
+        let records = || {
+            history.iter().map(|x| {
+                let linked = LinkedExperience {
+                    experience: x.clone(),
+                    links: vec![],
+                    retrievals: 0,
+                };
+                (linked, history.index_node(&x.id).cloned())
+            })
+        };
+        let mut parts = History::<8>::from_records(history.metadata(), records());
+        assert_eq!(
+            parts.related_with(&queries[0], 10, &options).unwrap(),
+            related
+        );
+        assert!(parts.take_changes().is_empty());
+        // and rebuilt, to be stored again, if a node is missing
+        let mut parts = History::<8>::from_records(
+            history.metadata(),
+            records().map(|(x, node)| {
+                if x.experience.id == id {
+                    (x, None)
+                } else {
+                    (x, node)
+                }
+            }),
+        );
+        assert_eq!(parts.index().unwrap().len(), history.len());
+        assert_eq!(parts.take_changes().len(), history.len());
+    }
+
+    #[test]
+    fn history_merges() {
+        let mut ours = History::<2>::new();
+        let mut theirs = History::<2>::new();
+        let id1 = ours
+            .push("q1", "r1", Embedding::new("", [0.0, 1.0]), vec![])
+            .unwrap();
+        let id2 = ours
+            .push("q2", "r2", Embedding::new("", [1.0, 0.0]), vec![id1])
+            .unwrap();
+        let id3 = theirs
+            .push("q3", "r3", Embedding::new("", [1.0, 1.0]), vec![])
+            .unwrap();
+        theirs
+            .push("q2", "r2", Embedding::new("", [1.0, 0.0]), vec![id3])
+            .unwrap();
+        let id4 = theirs
+            .push("q4", "r4", Embedding::new("", [0.0, 0.5]), vec![])
+            .unwrap();
+        ours.take_changes();
+
+        assert_eq!(ours.merge(theirs, MergePolicy::Append), 2);
+        assert_eq!(ours.len(), 4);
+        let ranks: Vec<u32> = [id1, id2, id3, id4]
+            .iter()
+            .map(|x| ours.get(x).unwrap().rank)
+            .collect();
+        assert_eq!(ranks, [0, 1, 2, 3]);
+        assert_eq!(ours.links(&id2).unwrap(), [id1, id3]);
+        assert_eq!(ours.metadata().last_id, Some(id4));
+        assert_eq!(ours.metadata().next_rank, 4);
+        let mut changes = ours.take_changes();
+        changes.sort();
+        let mut expected = vec![id2, id3, id4];
+        expected.sort();
+        assert_eq!(changes, expected);
+    }
+
+    #[test]
+    fn history_removes() {
+        let mut history = History::<2>::new();
+        let id1 = history
+            .push("q1", "r1", Embedding::new("", [0.0, 1.0]), vec![])
+            .unwrap();
+        let id2 = history
+            .push("q2", "r2", Embedding::new("", [1.0, 0.0]), vec![id1])
+            .unwrap();
+        assert_eq!(history.remove(&id2).unwrap().query, "q2");
+        assert!(history.remove(&id2).is_none());
+        assert_eq!(history.len(), 1);
+        // traversal starts from the remaining experience
+        let ids = history.related(&Embedding::new("", [1.0, 0.0]), 2).unwrap();
+        assert_eq!(related_ids(&ids), vec![id1]);
+        let history = History::<2>::deserialize(&history.serialize().unwrap()).unwrap();
+        assert_eq!(history.get(&id1).unwrap().response, "r1");
+    }
+
+    #[test]
+    fn history_tracks_changes() {
+        let mut history = History::<2>::new();
+        let id1 = history
+            .push("q1", "r1", Embedding::new("", [0.0, 1.0]), vec![])
+            .unwrap();
+        assert_eq!(history.take_changes(), vec![id1]);
+        assert!(history.take_changes().is_empty());
+        // pushing the same exchange with no new links changes nothing
+        history
+            .push("q1", "r1", Embedding::new("", [0.0, 1.0]), vec![])
+            .unwrap();
+        assert!(history.take_changes().is_empty());
+        history.remove(&id1);
+        assert_eq!(history.take_changes(), vec![id1]);
+
+        let id2 = history
+            .push("q2", "r2", Embedding::new("", [1.0, 0.0]), vec![])
+            .unwrap();
+        let parts = History::<2>::from_parts(
+            history.metadata(),
+            history.iter().map(|x| LinkedExperience {
+                experience: x.clone(),
+                links: vec![],
+                retrievals: 0,
+            }),
+        );
+        assert_eq!(parts.get(&id2).unwrap().query, "q2");
+        assert_eq!(parts.metadata().next_rank, 2);
+    }
+
+    #[test]
+    fn history_loads_without_persona() {
+        let mut history = History::<2>::new();
+        history
+            .push("q1", "r1", Embedding::new("", [0.0, 1.0]), vec![])
+            .unwrap();
+        // the history as serialized before it had a persona
+        let data =
+            rmp_serde::to_vec(&(&history.experiences, history.last_id, history.next_rank)).unwrap();
+        let history: History<2> = rmp_serde::from_slice(&data).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.persona(), None);
+    }
+
+    #[test]
+    #[allow(clippy::clone_on_copy)]
     fn history_pushes_and_gets_related() {
         let mut history = History::<2>::new();
         let e1 = Embedding::new("", [0.0, 1.0]);
@@ -188,9 +1458,9 @@ mod test {
             .unwrap();
         // gets id4, (id3, id1), stops at id3, ranks 3 over 4
         let ids = history.related(&Embedding::new("", [1.0, 0.0]), 2).unwrap();
-        assert_eq!(ids, vec![id3.clone(), id4.clone()]);
+        assert_eq!(related_ids(&ids), vec![id3.clone(), id4.clone()]);
         // gets id4, (id1, id3), stops at id1, ranks 1 over 4
         let ids = history.related(&Embedding::new("", [0.0, 1.0]), 2).unwrap();
-        assert_eq!(ids, vec![id1.clone(), id4.clone()]);
+        assert_eq!(related_ids(&ids), vec![id1.clone(), id4.clone()]);
     }
 }
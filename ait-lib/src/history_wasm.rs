@@ -1,19 +1,35 @@
+//! Bindings to the `history` module for the browser.
+
 use base64::{engine::general_purpose, Engine};
-use js_sys::{Array, JsString, Uint8Array};
+use js_sys::{Array, JsString, Object, Promise, Reflect, Uint8Array};
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::convert::TryFrom;
-use std::iter::FromIterator;
+use std::rc::Rc;
 use tap::Pipe;
 use wasm_bindgen::prelude::*;
-use web_sys::window;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{future_to_promise, JsFuture};
+use web_sys::{window, IdbDatabase, IdbRequest, IdbTransaction, IdbTransactionMode, Storage};
 
-use crate::gpt::{embedding_model_size, EmbeddingModel, GptEmbedding};
-use crate::history::{Error, History as HistoryRs};
-use crate::utils::TextId;
+use crate::export::VectorEncoding;
+use crate::gpt::{GptEmbedding, EMBED_DIMS, EMBED_MODEL};
+use crate::history::{Error, History as HistoryRs, MergePolicy, Related, RelatedOptions, Search};
+use crate::hnsw::HnswParams;
+use crate::prompt::{PromptBuilder, PromptOptions};
+use crate::store::{Error as StoreError, HistoryStore, StoreFormat};
+use crate::utils::{text_id_to_hex, TextId};
 
-const NDIMS: usize = embedding_model_size(EmbeddingModel::TextEmbeddingAda002);
+const NDIMS: usize = EMBED_DIMS;
 
 type Result<T> = core::result::Result<T, Error>;
 
+impl From<Error> for JsValue {
+    fn from(e: Error) -> Self {
+        JsValue::from_str(&e.to_string())
+    }
+}
+
 fn text_id_from_js(text_id_js: &Uint8Array) -> Result<[u8; 32]> {
     let mut text_id = [0u8; 32];
     if text_id_js.length() as usize != text_id.len() {
@@ -23,42 +39,498 @@ fn text_id_from_js(text_id_js: &Uint8Array) -> Result<[u8; 32]> {
     Ok(text_id)
 }
 
-#[wasm_bindgen]
-pub struct History(HistoryRs<NDIMS>);
+fn text_ids_from_js(text_ids_js: Vec<Uint8Array>) -> Result<Vec<TextId>> {
+    text_ids_js
+        .into_iter()
+        .map(|x| x.to_vec())
+        .map(TextId::try_from)
+        .map(|x| x.ok())
+        .collect::<Option<Vec<TextId>>>()
+        .ok_or(Error::InvalidTextId)
+}
 
-#[wasm_bindgen]
-impl History {
-    pub fn store(&self) -> Result<String> {
-        let data = rmp_serde::to_vec(&self.0).map_err(|_| Error::CantStoreHistory)?;
-        let data = general_purpose::STANDARD_NO_PAD.encode(data);
+/// Decode a history encoded as by `History.store`, or written as JSON by
+/// `History.export_json`, or by a JSON file store.
+fn decode_history(data: &str) -> Result<HistoryRs<NDIMS>> {
+    if data.trim_start().starts_with('{') {
+        return HistoryRs::read_json(data);
+    }
+    let data = general_purpose::STANDARD_NO_PAD
+        .decode(data.trim())
+        .map_err(|_| Error::CantAccessHistory)?;
+    HistoryRs::deserialize(&data)
+}
+
+/// A new history, whose embeddings are made by the model used by the UI.
+fn new_history() -> HistoryRs<NDIMS> {
+    let mut history = HistoryRs::new();
+    history.set_embedding_model(Some(EMBED_MODEL.name().to_string()));
+    history
+}
+
+/// Scoring options given as the fields of `Scoring`, with missing fields
+/// taking their default value, or `undefined` to rank by distance alone.
+fn related_options_from_js(options: JsValue) -> Result<RelatedOptions> {
+    serde_wasm_bindgen::from_value::<Option<RelatedOptions>>(options)
+        .map_err(|e| Error::InvalidOptions(e.to_string()))
+        .map(Option::unwrap_or_default)
+}
+
+/// Related experiences as an array of `{id, distance}` objects.
+fn related_to_js(related: Vec<Related>) -> Result<Array> {
+    related
+        .into_iter()
+        .map(|x| {
+            let object = Object::new();
+            Reflect::set(&object, &"id".into(), &Uint8Array::from(x.id.as_slice()))?;
+            Reflect::set(&object, &"distance".into(), &x.distance.into())?;
+            Ok(object)
+        })
+        .collect::<core::result::Result<Array, JsValue>>()
+        .map_err(|_| Error::CantAccessExperience)
+}
+
+/// Today's date, in the format `YYYY-MM-DD`.
+fn today() -> Option<String> {
+    js_sys::Date::new_0()
+        .to_iso_string()
+        .as_string()
+        .map(|x| x.chars().take(10).collect())
+}
+
+/// The `localStorage` key of the history used by the UI.
+const STORAGE_KEY: &str = "ait_history";
+
+/// Keeps a history in the browser's `localStorage`, as base64 encoded
+/// MessagePack.
+pub struct LocalStorageStore {
+    key: String,
+}
+
+impl LocalStorageStore {
+    pub fn new(key: &str) -> Self {
+        Self {
+            key: key.to_string(),
+        }
+    }
+
+    fn storage() -> core::result::Result<Storage, StoreError> {
         window()
             .and_then(|x| x.local_storage().ok())
             .flatten()
-            .map(|x| x.set_item("ait_history", &data).ok())
-            .flatten()
-            .ok_or(Error::CantStoreHistory)?;
-        Ok(data)
+            .ok_or(StoreError::Unavailable)
     }
 
-    pub fn load() -> Result<History> {
-        let data = window()
-            .and_then(|x| x.local_storage().ok())
-            .flatten()
-            .and_then(|x| match x.get_item("ait_history") {
-                Ok(Some(data)) => Some(data),
-                Ok(None) => {
-                    let data = History(HistoryRs::new()).store();
-                    data.ok()
-                }
-                Err(_) => None,
-            })
-            .ok_or(Error::CantAccessHistory)?;
+    /// The history as it is written to the storage.
+    pub fn encode<const N: usize>(
+        history: &HistoryRs<N>,
+    ) -> core::result::Result<String, StoreError> {
+        let data = StoreFormat::MessagePack.serialize(history)?;
+        Ok(general_purpose::STANDARD_NO_PAD.encode(data))
+    }
+}
+
+impl<const N: usize> HistoryStore<N> for LocalStorageStore {
+    fn load(&self) -> core::result::Result<Option<HistoryRs<N>>, StoreError> {
+        let data = match Self::storage()?.get_item(&self.key) {
+            Ok(Some(data)) => data,
+            Ok(None) => return Ok(None),
+            Err(_) => return Err(StoreError::Unavailable),
+        };
         let data = general_purpose::STANDARD_NO_PAD
             .decode(data)
-            .map_err(|_| Error::CantAccessHistory)?;
-        rmp_serde::from_slice(&data)
-            .map(History)
-            .map_err(|_| Error::CantAccessHistory)
+            .map_err(|e| StoreError::CantDeserialize(e.to_string()))?;
+        StoreFormat::MessagePack.deserialize(&data).map(Some)
+    }
+
+    fn store(&self, history: &HistoryRs<N>) -> core::result::Result<(), StoreError> {
+        let data = Self::encode(history)?;
+        Self::storage()?
+            .set_item(&self.key, &data)
+            .map_err(|_| StoreError::Unavailable)
+    }
+}
+
+impl LocalStorageStore {
+    pub fn remove(&self) -> core::result::Result<(), StoreError> {
+        Self::storage()?
+            .remove_item(&self.key)
+            .map_err(|_| StoreError::Unavailable)
+    }
+}
+
+const DB_NAME: &str = "ait";
+const DB_VERSION: u32 = 1;
+/// The object store of the experiences and their links, keyed by the
+/// hexadecimal text ID.
+const EXPERIENCES: &str = "experiences";
+/// The object store of the history's metadata, under `METADATA_KEY`.
+const METADATA: &str = "metadata";
+const METADATA_KEY: &str = "history";
+
+fn js_error(e: JsValue) -> StoreError {
+    StoreError::Failed(format!("{:?}", e))
+}
+
+/// Records are kept in the versioned format of `StoreFormat`, so that they
+/// are upgraded as the format changes.
+const RECORD_FORMAT: StoreFormat = StoreFormat::MessagePack;
+
+/// The record of the experience `id` of `history`, with its node in the
+/// index, or `None` if it was removed.
+fn encode_experience<const N: usize>(
+    history: &HistoryRs<N>,
+    id: &TextId,
+) -> core::result::Result<Option<Uint8Array>, StoreError> {
+    let experience = match history.linked(id) {
+        Some(experience) => experience,
+        None => return Ok(None),
+    };
+    let data = RECORD_FORMAT.serialize_experience(experience, history.index_node(id))?;
+    Ok(Some(Uint8Array::from(data.as_slice())))
+}
+
+fn encode_metadata<const N: usize>(
+    history: &HistoryRs<N>,
+) -> core::result::Result<Uint8Array, StoreError> {
+    let data = RECORD_FORMAT.serialize_metadata::<N>(&history.metadata())?;
+    Ok(Uint8Array::from(data.as_slice()))
+}
+
+/// Wait for the result of an IndexedDB `request`.
+async fn request_result(request: &IdbRequest) -> core::result::Result<JsValue, StoreError> {
+    let promise = Promise::new(&mut |resolve, reject| {
+        let succeeded = request.clone();
+        let onsuccess = Closure::once_into_js(move || {
+            let result = succeeded.result().unwrap_or(JsValue::UNDEFINED);
+            let _ = resolve.call1(&JsValue::NULL, &result);
+        });
+        let failed = request.clone();
+        let onerror = Closure::once_into_js(move || {
+            let error = failed.error().ok().flatten().map(JsValue::from);
+            let _ = reject.call1(&JsValue::NULL, &error.unwrap_or(JsValue::UNDEFINED));
+        });
+        request.set_onsuccess(Some(onsuccess.unchecked_ref()));
+        request.set_onerror(Some(onerror.unchecked_ref()));
+    });
+    JsFuture::from(promise).await.map_err(js_error)
+}
+
+/// Wait for the `transaction` to commit.
+async fn transaction_complete(
+    transaction: &IdbTransaction,
+) -> core::result::Result<(), StoreError> {
+    let promise = Promise::new(&mut |resolve, reject| {
+        let oncomplete = Closure::once_into_js(move || {
+            let _ = resolve.call0(&JsValue::NULL);
+        });
+        // an error aborts the transaction, so both handlers can be called
+        let failed = transaction.clone();
+        let onabort = Closure::<dyn FnMut()>::new(move || {
+            let error = failed.error().map(JsValue::from);
+            let _ = reject.call1(&JsValue::NULL, &error.unwrap_or(JsValue::UNDEFINED));
+        })
+        .into_js_value();
+        transaction.set_oncomplete(Some(oncomplete.unchecked_ref()));
+        transaction.set_onerror(Some(onabort.unchecked_ref()));
+        transaction.set_onabort(Some(onabort.unchecked_ref()));
+    });
+    JsFuture::from(promise).await.map(|_| ()).map_err(js_error)
+}
+
+/// The records to write to an `IndexedDbStore`.
+pub struct Changes {
+    /// Remove all records before writing.
+    clear: bool,
+    /// The experiences to write, or to delete when `None`, by key.
+    experiences: Vec<(String, Option<Uint8Array>)>,
+    metadata: Uint8Array,
+}
+
+impl Changes {
+    /// The experiences `ids` of `history`, which changed since they were
+    /// last written.
+    pub fn of<const N: usize>(
+        history: &HistoryRs<N>,
+        ids: &HashSet<TextId>,
+    ) -> core::result::Result<Self, StoreError> {
+        let experiences = ids
+            .iter()
+            .map(|id| {
+                let record = encode_experience(history, id)?;
+                Ok((text_id_to_hex(id), record))
+            })
+            .collect::<core::result::Result<_, StoreError>>()?;
+        Ok(Self {
+            clear: false,
+            experiences,
+            metadata: encode_metadata(history)?,
+        })
+    }
+
+    /// All of the experiences of `history`, replacing those stored.
+    pub fn everything<const N: usize>(
+        history: &mut HistoryRs<N>,
+    ) -> core::result::Result<Self, StoreError> {
+        history.take_changes();
+        let experiences = history
+            .iter()
+            .map(|x| {
+                let record = encode_experience(history, &x.id)?;
+                Ok((text_id_to_hex(&x.id), record))
+            })
+            .collect::<core::result::Result<_, StoreError>>()?;
+        Ok(Self {
+            clear: true,
+            experiences,
+            metadata: encode_metadata(history)?,
+        })
+    }
+}
+
+/// Keeps a history in the browser's IndexedDB, with a record per
+/// experience, so that saving a history writes only the experiences which
+/// changed. It has room for histories much larger than `localStorage`.
+#[derive(Clone)]
+pub struct IndexedDbStore {
+    db: IdbDatabase,
+    /// The experiences whose last write failed, so that they are written
+    /// with the next changes.
+    unsaved: Rc<RefCell<HashSet<TextId>>>,
+}
+
+impl IndexedDbStore {
+    pub async fn open() -> core::result::Result<Self, StoreError> {
+        let factory = window()
+            .and_then(|x| x.indexed_db().ok())
+            .flatten()
+            .ok_or(StoreError::Unavailable)?;
+        let request = factory
+            .open_with_u32(DB_NAME, DB_VERSION)
+            .map_err(js_error)?;
+        let upgraded = request.clone();
+        let onupgradeneeded = Closure::once_into_js(move || {
+            if let Some(db) = upgraded
+                .result()
+                .ok()
+                .and_then(|x| x.dyn_into::<IdbDatabase>().ok())
+            {
+                let _ = db.create_object_store(EXPERIENCES);
+                let _ = db.create_object_store(METADATA);
+            }
+        });
+        request.set_onupgradeneeded(Some(onupgradeneeded.unchecked_ref()));
+        let db = request_result(&request)
+            .await?
+            .dyn_into::<IdbDatabase>()
+            .map_err(js_error)?;
+        Ok(Self {
+            db,
+            unsaved: Rc::default(),
+        })
+    }
+
+    fn transaction(
+        &self,
+        mode: IdbTransactionMode,
+    ) -> core::result::Result<IdbTransaction, StoreError> {
+        let names = Array::of2(&EXPERIENCES.into(), &METADATA.into());
+        self.db
+            .transaction_with_str_sequence_and_mode(&names, mode)
+            .map_err(js_error)
+    }
+
+    /// Read the stored history, or `None` if no history was stored. Records
+    /// stored in an older version of the format are upgraded.
+    pub async fn load<const N: usize>(
+        &self,
+    ) -> core::result::Result<Option<HistoryRs<N>>, StoreError> {
+        let transaction = self.transaction(IdbTransactionMode::Readonly)?;
+        // both requests are made before waiting, while the transaction is active
+        let metadata = transaction
+            .object_store(METADATA)
+            .and_then(|x| x.get(&METADATA_KEY.into()))
+            .map_err(js_error)?;
+        let experiences = transaction
+            .object_store(EXPERIENCES)
+            .and_then(|x| x.get_all())
+            .map_err(js_error)?;
+        let metadata = request_result(&metadata).await?;
+        let experiences = request_result(&experiences).await?;
+        if metadata.is_undefined() {
+            return Ok(None);
+        }
+        let metadata = Uint8Array::new(&metadata).to_vec();
+        let experiences: Vec<Vec<u8>> = Array::from(&experiences)
+            .iter()
+            .map(|x| Uint8Array::new(&x).to_vec())
+            .collect();
+        RECORD_FORMAT
+            .deserialize_records(&metadata, &experiences)
+            .map(Some)
+    }
+
+    pub async fn write(&self, changes: Changes) -> core::result::Result<(), StoreError> {
+        let transaction = self.transaction(IdbTransactionMode::Readwrite)?;
+        let experiences = transaction.object_store(EXPERIENCES).map_err(js_error)?;
+        let metadata = transaction.object_store(METADATA).map_err(js_error)?;
+        if changes.clear {
+            experiences.clear().map_err(js_error)?;
+        }
+        for (key, record) in changes.experiences {
+            let key = JsValue::from(key);
+            match record {
+                Some(record) => experiences.put_with_key(&record, &key),
+                None => experiences.delete(&key),
+            }
+            .map_err(js_error)?;
+        }
+        metadata
+            .put_with_key(&changes.metadata, &METADATA_KEY.into())
+            .map_err(js_error)?;
+        transaction_complete(&transaction).await
+    }
+}
+
+/// A history, kept in IndexedDB when it is opened with `History.open`, and
+/// in `localStorage` otherwise.
+#[wasm_bindgen]
+pub struct History(HistoryRs<NDIMS>, Option<IndexedDbStore>);
+
+impl History {
+    /// Replace the history kept in IndexedDB with `history`.
+    async fn keep(mut history: HistoryRs<NDIMS>) -> Result<History> {
+        let store = IndexedDbStore::open().await?;
+        let changes = Changes::everything(&mut history).map_err(|_| Error::CantStoreHistory)?;
+        store
+            .write(changes)
+            .await
+            .map_err(|_| Error::CantStoreHistory)?;
+        Ok(History(history, Some(store)))
+    }
+}
+
+#[wasm_bindgen]
+impl History {
+    pub fn store(&self) -> Result<String> {
+        let store = LocalStorageStore::new(STORAGE_KEY);
+        store.store(&self.0).map_err(|_| Error::CantStoreHistory)?;
+        LocalStorageStore::encode(&self.0).map_err(|_| Error::CantStoreHistory)
+    }
+
+    pub fn load() -> Result<History> {
+        let store = LocalStorageStore::new(STORAGE_KEY);
+        match store.load().map_err(Error::from)? {
+            Some(history) => Ok(History(history, None)),
+            None => {
+                let history = History(new_history(), None);
+                history.store()?;
+                Ok(history)
+            }
+        }
+    }
+
+    /// Open the history kept in IndexedDB. A history kept in `localStorage`
+    /// is moved to IndexedDB. When no history was kept, the `default_history`,
+    /// encoded as by `store`, is kept. A history in `localStorage` which can't
+    /// be read is left there, and its error returned.
+    pub async fn open(default_history: Option<String>) -> Result<History> {
+        let store = IndexedDbStore::open().await?;
+        if let Some(history) = store.load().await? {
+            return Ok(History(history, Some(store)));
+        }
+        let local = LocalStorageStore::new(STORAGE_KEY);
+        let migrated = match local.load() {
+            Ok(history) => history,
+            Err(StoreError::Unavailable) => None,
+            Err(e) => return Err(e.into()),
+        };
+        let is_migrated = migrated.is_some();
+        let mut history = match (migrated, default_history) {
+            (Some(history), _) => history,
+            (None, Some(default_history)) => decode_history(&default_history)?,
+            (None, None) => new_history(),
+        };
+        let changes = Changes::everything(&mut history).map_err(|_| Error::CantStoreHistory)?;
+        store
+            .write(changes)
+            .await
+            .map_err(|_| Error::CantStoreHistory)?;
+        // free the quota once the history is safely moved
+        if is_migrated {
+            let _ = local.remove();
+        }
+        Ok(History(history, Some(store)))
+    }
+
+    /// Replace the history kept in IndexedDB with the `history` encoded as by
+    /// `store` or `export_json`, or with an empty history.
+    pub async fn replace(history: Option<String>) -> Result<History> {
+        let history = match history {
+            Some(history) => decode_history(&history)?,
+            None => new_history(),
+        };
+        History::keep(history).await
+    }
+
+    /// Replace the history kept in IndexedDB with the `json` written by
+    /// `export_json`.
+    pub async fn import_json(json: String) -> Result<History> {
+        History::keep(HistoryRs::import_json(&json)?).await
+    }
+
+    /// Write the history as readable JSON, with embeddings as arrays of
+    /// numbers, or as base64 if `base64_vectors`.
+    pub fn export_json(&self, base64_vectors: bool) -> Result<String> {
+        let vectors = if base64_vectors {
+            VectorEncoding::Base64
+        } else {
+            VectorEncoding::Array
+        };
+        Ok(self.0.export_json(vectors)?)
+    }
+
+    /// Add the experiences of the `other` history, encoded as by `store` or
+    /// `export_json`, after this history's, or before them if `prepend`.
+    /// Returns how many were new. The merge is kept on the next `save`.
+    pub fn merge(&mut self, other: String, prepend: bool) -> Result<usize> {
+        let policy = if prepend {
+            MergePolicy::Prepend
+        } else {
+            MergePolicy::Append
+        };
+        Ok(self.0.merge(decode_history(&other)?, policy))
+    }
+
+    /// Write the changes made since the last save. Only the experiences which
+    /// changed are written to IndexedDB, whereas `localStorage` is rewritten.
+    /// Experiences which fail to be written are written on the next save.
+    pub fn save(&mut self) -> Promise {
+        let store = match &self.1 {
+            Some(store) => store.clone(),
+            None => {
+                // the whole history is written, so no change is left to write
+                let stored = self.store().map(|_| {
+                    self.0.take_changes();
+                    JsValue::UNDEFINED
+                });
+                return future_to_promise(async move { stored.map_err(JsValue::from) });
+            }
+        };
+        let mut ids = store.unsaved.take();
+        ids.extend(self.0.take_changes());
+        let changes = Changes::of(&self.0, &ids);
+        future_to_promise(async move {
+            let written = match changes {
+                Ok(changes) => store.write(changes).await,
+                Err(e) => Err(e),
+            };
+            if written.is_err() {
+                store.unsaved.borrow_mut().extend(ids);
+            }
+            written.map_err(|_| Error::CantStoreHistory)?;
+            Ok(JsValue::UNDEFINED)
+        })
     }
 
     pub fn push(
@@ -70,27 +542,87 @@ impl History {
     ) -> Result<Uint8Array> {
         let embedding =
             GptEmbedding::deserialize(&embedding.to_vec()).map_err(|_| Error::InvalidEmbedding)?;
-        let links: Vec<TextId> = links
-            .into_iter()
-            .map(|x| x.to_vec())
-            .map(TextId::try_from)
-            .map(|x| x.ok())
-            .collect::<Option<Vec<TextId>>>()
-            .ok_or(Error::InvalidTextId)?;
+        let links = text_ids_from_js(links)?;
         self.0
             .push(query, response, embedding, links)
             .map(|x| Uint8Array::from(x.as_slice()))
     }
 
-    pub fn related_ids(&self, embedding: &Uint8Array, num: u32) -> Result<Array> {
+    /// Count a retrieval of each of the experiences `text_ids`, once they are
+    /// given as context.
+    pub fn record_retrieval(&mut self, text_ids: Vec<Uint8Array>) -> Result<()> {
+        let text_ids = text_ids_from_js(text_ids)?;
+        self.0.record_retrieval(&text_ids);
+        Ok(())
+    }
+
+    /// Remove the experience `text_id`, and return whether it was in the
+    /// history.
+    pub fn remove(&mut self, text_id: &Uint8Array) -> Result<bool> {
+        let text_id = text_id_from_js(text_id)?;
+        Ok(self.0.remove(&text_id).is_some())
+    }
+
+    /// Replace the response of the experience `text_id`, and return its new
+    /// ID.
+    pub fn update_response(
+        &mut self,
+        text_id: &Uint8Array,
+        response: &str,
+        embedding: Uint8Array,
+    ) -> Result<Uint8Array> {
+        let text_id = text_id_from_js(text_id)?;
         let embedding =
             GptEmbedding::deserialize(&embedding.to_vec()).map_err(|_| Error::InvalidEmbedding)?;
         self.0
-            .related(&embedding, num as usize)?
-            .into_iter()
+            .update_response(&text_id, response, embedding)
             .map(|x| Uint8Array::from(x.as_slice()))
-            .pipe(Array::from_iter)
-            .pipe(Ok)
+    }
+
+    /// The experiences most related to `embedding`, as `{id, distance}`
+    /// objects, most related first. The `options` take the fields of
+    /// `RelatedOptions`, such as `{search: {hybrid: {global: 4}}, mmr_lambda:
+    /// 0.7}`, and missing fields their default value.
+    pub fn related_ids(&self, embedding: &Uint8Array, num: u32, options: JsValue) -> Result<Array> {
+        let embedding =
+            GptEmbedding::deserialize(&embedding.to_vec()).map_err(|_| Error::InvalidEmbedding)?;
+        let options = related_options_from_js(options)?;
+        related_to_js(self.0.related_with(&embedding, num as usize, &options)?)
+    }
+
+    /// The `num` experiences nearest to `embedding`, as by `related_ids`,
+    /// found with the index if it is enabled, and by scoring every experience
+    /// otherwise. The `search` of the `options` is ignored.
+    pub fn nearest_ids(&self, embedding: &Uint8Array, num: u32, options: JsValue) -> Result<Array> {
+        let embedding =
+            GptEmbedding::deserialize(&embedding.to_vec()).map_err(|_| Error::InvalidEmbedding)?;
+        let options = RelatedOptions {
+            search: Search::Approximate,
+            ..related_options_from_js(options)?
+        };
+        related_to_js(self.0.related_with(&embedding, num as usize, &options)?)
+    }
+
+    /// Index the experiences, so that `nearest_ids` doesn't score every
+    /// experience. The index is kept with the history on the next `save`.
+    pub fn enable_index(&mut self, m: u32, ef_construction: u32, ef_search: u32) {
+        self.0.set_index(Some(HnswParams {
+            m: m as usize,
+            ef_construction: ef_construction as usize,
+            ef_search: ef_search as usize,
+        }));
+    }
+
+    pub fn disable_index(&mut self) {
+        self.0.set_index(None);
+    }
+
+    /// The fraction of the `num` experiences nearest to `embedding` which the
+    /// index finds, or `undefined` if the index isn't enabled.
+    pub fn index_recall(&self, embedding: &Uint8Array, num: u32) -> Result<Option<f32>> {
+        let embedding =
+            GptEmbedding::deserialize(&embedding.to_vec()).map_err(|_| Error::InvalidEmbedding)?;
+        Ok(self.0.index_recall(&embedding, num as usize))
     }
 
     pub fn get_query(&self, text_id: &Uint8Array) -> Result<JsString> {
@@ -124,7 +656,56 @@ impl History {
             .pipe(Ok)
     }
 
+    pub fn persona(&self) -> Option<String> {
+        self.0.persona().map(str::to_string)
+    }
+
+    pub fn set_persona(&mut self, persona: Option<String>) {
+        self.0.set_persona(persona);
+    }
+
+    /// Render this history's persona, or the default system prompt, with
+    /// today's date, the `user_name` and the number of experiences.
+    pub fn system_prompt(&self, user_name: Option<String>) -> String {
+        let options = PromptOptions {
+            date: today(),
+            user_name,
+            ..Default::default()
+        };
+        PromptBuilder::new(&self.0, options).system_prompt()
+    }
+
+    /// Build the chat messages which ask `query` following the experiences of
+    /// `context_ids`, ordered from most to least relevant. The `options` take
+    /// the fields of `PromptOptions`, and missing fields their default value.
+    pub fn build_prompt(
+        &self,
+        query: &str,
+        context_ids: Vec<Uint8Array>,
+        options: JsValue,
+    ) -> Result<Array> {
+        let context_ids = text_ids_from_js(context_ids)?;
+        let mut options = serde_wasm_bindgen::from_value::<Option<PromptOptions>>(options)
+            .map_err(|e| Error::CantBuildMessages(e.to_string()))?
+            .unwrap_or_default();
+        options.date = options.date.or_else(today);
+        let prompt = PromptBuilder::new(&self.0, options)
+            .build_with_context(query, &context_ids)
+            .map_err(|e| Error::CantBuildMessages(e.to_string()))?;
+        prompt
+            .messages
+            .iter()
+            .map(|x| {
+                serde_wasm_bindgen::to_value(x).map_err(|e| Error::CantBuildMessages(e.to_string()))
+            })
+            .collect::<Result<Array>>()
+    }
+
     pub fn len(&self) -> u32 {
         self.0.len() as u32
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 }
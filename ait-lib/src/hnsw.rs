@@ -0,0 +1,449 @@
+//! A hierarchical navigable small world (HNSW) graph, which finds the
+//! approximate nearest neighbours of a vector without scoring every vector.
+//!
+//! The graph holds only text IDs. Distances are given by the caller, so that
+//! the vectors stay in the history.
+
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::convert::TryInto;
+
+use crate::utils::{TextId, TextIdHex};
+
+/// The highest layer a node can be placed in.
+const MAX_LEVEL: usize = 16;
+
+/// Tunes the trade-off between the speed and the recall of the index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HnswParams {
+    /// The links kept by each node in each layer, twice this in the bottom
+    /// layer. More links improve recall, at the cost of memory and speed.
+    pub m: usize,
+    /// The candidates considered when linking a new node.
+    pub ef_construction: usize,
+    /// The candidates considered when searching. Searches for more than this
+    /// many neighbours consider as many candidates as neighbours.
+    pub ef_search: usize,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        Self {
+            m: 16,
+            ef_construction: 100,
+            ef_search: 64,
+        }
+    }
+}
+
+/// A node and its distance from whatever is being searched for, ordered by
+/// distance.
+#[derive(Debug, Clone, Copy)]
+struct Scored(f32, TextId);
+
+impl PartialEq for Scored {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+/// The links of a node in each of the layers it is in, from the bottom up.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Node {
+    #[serde_as(as = "Vec<Vec<TextIdHex>>")]
+    layers: Vec<Vec<TextId>>,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hnsw {
+    params: HnswParams,
+    #[serde_as(as = "HashMap<TextIdHex, _>")]
+    nodes: HashMap<TextId, Node>,
+    /// A node in the top layer, where searches start.
+    #[serde_as(as = "Option<TextIdHex>")]
+    entry: Option<TextId>,
+}
+
+impl Hnsw {
+    pub fn new(params: HnswParams) -> Self {
+        Self {
+            params,
+            nodes: HashMap::new(),
+            entry: None,
+        }
+    }
+
+    /// Assemble a graph from its `nodes` and `entry`, as kept by a store
+    /// which keeps them apart.
+    pub fn from_nodes(
+        params: HnswParams,
+        nodes: HashMap<TextId, Node>,
+        entry: Option<TextId>,
+    ) -> Self {
+        Self {
+            params,
+            nodes,
+            entry,
+        }
+    }
+
+    pub fn params(&self) -> HnswParams {
+        self.params
+    }
+
+    /// Change the candidates considered when searching. Unlike the other
+    /// parameters, it doesn't require the index to be rebuilt.
+    pub fn set_ef_search(&mut self, ef_search: usize) {
+        self.params.ef_search = ef_search;
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn contains(&self, id: &TextId) -> bool {
+        self.nodes.contains_key(id)
+    }
+
+    pub fn node(&self, id: &TextId) -> Option<&Node> {
+        self.nodes.get(id)
+    }
+
+    /// The node in the top layer where searches start.
+    pub fn entry(&self) -> Option<TextId> {
+        self.entry
+    }
+
+    /// Add the node `id`, given the `distance` between any two nodes.
+    /// Returns the nodes whose links changed, including `id`.
+    pub fn insert(
+        &mut self,
+        id: TextId,
+        distance: &impl Fn(&TextId, &TextId) -> f32,
+    ) -> Vec<TextId> {
+        if self.nodes.contains_key(&id) {
+            return vec![];
+        }
+        let level = self.level_of(&id);
+        let entry = match self.valid_entry() {
+            Some(entry) => entry,
+            None => {
+                self.nodes.insert(
+                    id,
+                    Node {
+                        layers: vec![vec![]; level + 1],
+                    },
+                );
+                self.entry = Some(id);
+                return vec![id];
+            }
+        };
+        self.entry = Some(entry);
+        let query = |x: &TextId| distance(&id, x);
+        let top = self.level(&entry);
+        let mut entries = vec![entry];
+        for layer in (level + 1..=top).rev() {
+            entries = self.closest(&entries, layer, &query);
+        }
+        let mut layers = vec![vec![]; level + 1];
+        for layer in (0..=level.min(top)).rev() {
+            let found = self.search_layer(&entries, self.params.ef_construction, layer, &query);
+            layers[layer] = found
+                .iter()
+                .take(self.max_links(layer))
+                .map(|x| x.1)
+                .collect();
+            entries = found.into_iter().map(|x| x.1).collect();
+        }
+        self.nodes.insert(
+            id,
+            Node {
+                layers: layers.clone(),
+            },
+        );
+        let mut changed = vec![id];
+        for (layer, neighbours) in layers.iter().enumerate() {
+            for neighbour in neighbours {
+                self.link(neighbour, &[id], layer, distance);
+                if !changed.contains(neighbour) {
+                    changed.push(*neighbour);
+                }
+            }
+        }
+        if level > top {
+            self.entry = Some(id);
+        }
+        changed
+    }
+
+    /// Remove the node `id`. Nodes which linked to it are linked to its
+    /// neighbours instead, so that the graph stays connected. Returns the
+    /// nodes whose links changed.
+    pub fn remove(
+        &mut self,
+        id: &TextId,
+        distance: &impl Fn(&TextId, &TextId) -> f32,
+    ) -> Vec<TextId> {
+        let removed = match self.nodes.remove(id) {
+            Some(node) => node,
+            None => return vec![],
+        };
+        // links aren't always mutual, so any node could link to the removed node
+        let linking: Vec<(TextId, usize)> = self
+            .nodes
+            .iter()
+            .flat_map(|(node_id, node)| {
+                node.layers
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, links)| links.contains(id))
+                    .map(move |(layer, _)| (*node_id, layer))
+            })
+            .collect();
+        let mut changed = vec![];
+        for (node_id, layer) in linking {
+            let links = &mut self.nodes.get_mut(&node_id).unwrap().layers[layer];
+            links.retain(|x| x != id);
+            let neighbours = removed.layers.get(layer).map(Vec::as_slice);
+            self.link(&node_id, neighbours.unwrap_or_default(), layer, distance);
+            if !changed.contains(&node_id) {
+                changed.push(node_id);
+            }
+        }
+        if self.entry.as_ref() == Some(id) {
+            self.entry = self.top_node();
+        }
+        changed
+    }
+
+    /// The `num` nodes nearest to a vector and their distances, nearest
+    /// first, given the `distance` from the vector to any node.
+    pub fn search(&self, num: usize, distance: &impl Fn(&TextId) -> f32) -> Vec<(TextId, f32)> {
+        let entry = match self.valid_entry() {
+            Some(entry) => entry,
+            None => return vec![],
+        };
+        let mut entries = vec![entry];
+        for layer in (1..=self.level(&entry)).rev() {
+            entries = self.closest(&entries, layer, distance);
+        }
+        let ef = self.params.ef_search.max(num);
+        self.search_layer(&entries, ef, 0, distance)
+            .into_iter()
+            .take(num)
+            .map(|x| (x.1, x.0))
+            .collect()
+    }
+
+    fn level(&self, id: &TextId) -> usize {
+        self.nodes
+            .get(id)
+            .map_or(0, |x| x.layers.len().saturating_sub(1))
+    }
+
+    /// The node in the most layers, which is where searches start.
+    fn top_node(&self) -> Option<TextId> {
+        self.nodes
+            .iter()
+            .max_by(|(a_id, a), (b_id, b)| a.layers.len().cmp(&b.layers.len()).then(a_id.cmp(b_id)))
+            .map(|(x, _)| *x)
+    }
+
+    /// The entry node, or the top node if the entry isn't in the graph, as
+    /// can happen with a graph which was stored.
+    fn valid_entry(&self) -> Option<TextId> {
+        match self.entry {
+            Some(entry) if self.nodes.contains_key(&entry) => Some(entry),
+            _ => self.top_node(),
+        }
+    }
+
+    fn max_links(&self, layer: usize) -> usize {
+        if layer == 0 {
+            2 * self.params.m
+        } else {
+            self.params.m
+        }
+    }
+
+    /// The layers a node is placed in are drawn from an exponential
+    /// distribution. Text IDs are hashes, so they are used as the random
+    /// numbers, which places a node in the same layers whenever it is added.
+    fn level_of(&self, id: &TextId) -> usize {
+        let bits = u64::from_le_bytes(id[..8].try_into().unwrap());
+        // uniform in (0, 1]
+        let uniform = ((bits >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        let scale = 1.0 / (self.params.m.max(2) as f64).ln();
+        ((-uniform.ln() * scale) as usize).min(MAX_LEVEL)
+    }
+
+    /// Link `from` to `to` in `layer`, keeping only its nearest links.
+    fn link(
+        &mut self,
+        from: &TextId,
+        to: &[TextId],
+        layer: usize,
+        distance: &impl Fn(&TextId, &TextId) -> f32,
+    ) {
+        let max_links = self.max_links(layer);
+        let links = match self
+            .nodes
+            .get_mut(from)
+            .and_then(|x| x.layers.get_mut(layer))
+        {
+            Some(links) => links,
+            None => return,
+        };
+        for id in to {
+            if id != from && !links.contains(id) {
+                links.push(*id);
+            }
+        }
+        if links.len() > max_links {
+            let mut scored: Vec<Scored> = links
+                .iter()
+                .map(|x| Scored(distance(from, x), *x))
+                .collect();
+            scored.sort();
+            *links = scored.into_iter().take(max_links).map(|x| x.1).collect();
+        }
+    }
+
+    /// The node nearest to the vector in `layer`, found greedily from
+    /// `entries`.
+    fn closest(
+        &self,
+        entries: &[TextId],
+        layer: usize,
+        distance: &impl Fn(&TextId) -> f32,
+    ) -> Vec<TextId> {
+        self.search_layer(entries, 1, layer, distance)
+            .into_iter()
+            .map(|x| x.1)
+            .collect()
+    }
+
+    /// The `ef` nodes nearest to the vector in `layer`, nearest first, found
+    /// by expanding the nearest candidates from `entries`.
+    fn search_layer(
+        &self,
+        entries: &[TextId],
+        ef: usize,
+        layer: usize,
+        distance: &impl Fn(&TextId) -> f32,
+    ) -> Vec<Scored> {
+        let mut visited: HashSet<TextId> = entries.iter().copied().collect();
+        let mut candidates: BinaryHeap<Reverse<Scored>> = BinaryHeap::new();
+        let mut found: BinaryHeap<Scored> = BinaryHeap::new();
+        for id in entries {
+            let scored = Scored(distance(id), *id);
+            candidates.push(Reverse(scored));
+            found.push(scored);
+        }
+        while found.len() > ef {
+            found.pop();
+        }
+        while let Some(Reverse(candidate)) = candidates.pop() {
+            match found.peek() {
+                Some(furthest) if found.len() >= ef && candidate.0 > furthest.0 => break,
+                _ => {}
+            }
+            let links = self
+                .nodes
+                .get(&candidate.1)
+                .and_then(|x| x.layers.get(layer));
+            for id in links.into_iter().flatten() {
+                if !visited.insert(*id) {
+                    continue;
+                }
+                let scored = Scored(distance(id), *id);
+                if found.len() < ef || found.peek().is_none_or(|x| scored < *x) {
+                    candidates.push(Reverse(scored));
+                    found.push(scored);
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+        found.into_sorted_vec()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::new_text_id;
+
+    #[test]
+    fn hnsw_finds_nearest_points() {
+        let points: HashMap<TextId, f32> = (0..200)
+            .map(|x| (new_text_id(&[&x.to_string()]), x as f32))
+            .collect();
+        let distance = |a: &TextId, b: &TextId| (points[a] - points[b]).abs();
+        let mut index = Hnsw::new(HnswParams {
+            m: 4,
+            ..Default::default()
+        });
+        for id in points.keys() {
+            index.insert(*id, &distance);
+        }
+        assert_eq!(index.len(), 200);
+
+        let found = index.search(3, &|x| (points[x] - 50.2).abs());
+        let found: Vec<f32> = found.iter().map(|(x, _)| points[x]).collect();
+        assert_eq!(found, [50.0, 51.0, 49.0]);
+
+        // a graph assembled from its nodes finds the same
+        let nodes = points
+            .keys()
+            .map(|x| (*x, index.node(x).unwrap().clone()))
+            .collect();
+        let assembled = Hnsw::from_nodes(index.params(), nodes, index.entry());
+        let found = assembled.search(3, &|x| (points[x] - 50.2).abs());
+        let found: Vec<f32> = found.iter().map(|(x, _)| points[x]).collect();
+        assert_eq!(found, [50.0, 51.0, 49.0]);
+
+        let id = new_text_id(&["50"]);
+        let mut linking: Vec<TextId> = index
+            .nodes
+            .iter()
+            .filter(|(_, x)| x.layers.iter().any(|links| links.contains(&id)))
+            .map(|(x, _)| *x)
+            .collect();
+        let mut changed = index.remove(&id, &distance);
+        assert!(!index.contains(&id));
+        changed.sort();
+        linking.sort();
+        assert_eq!(changed, linking);
+        let found = index.search(2, &|x| (points[x] - 50.2).abs());
+        let found: Vec<f32> = found.iter().map(|(x, _)| points[x]).collect();
+        assert_eq!(found, [51.0, 49.0]);
+
+        // a stale entry, as in a stored graph, is replaced
+        index.entry = Some(id);
+        assert_eq!(index.search(1, &|x| (points[x] - 50.2).abs()).len(), 1);
+    }
+}
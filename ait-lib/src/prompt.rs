@@ -0,0 +1,523 @@
+//! Build the prompts sent to chat models.
+
+use serde::{Deserialize, Serialize};
+
+use crate::embedding::Embedding;
+use crate::gpt::{ChatCompletionMessage, ChatCompletionMessageRole, ChatCompletionModel};
+use crate::history::{self, History, RelatedOptions};
+use crate::tokens::{token_counter, TokenCounter, DEFAULT_CONTEXT_WINDOW, MESSAGE_OVERHEAD_TOKENS};
+use crate::utils::TextId;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("the prompt needs {tokens} tokens but only {budget} are available")]
+    PromptTooLong { tokens: usize, budget: usize },
+    #[error(transparent)]
+    HistoryError(#[from] history::Error),
+}
+
+type Result<T> = core::result::Result<T, Error>;
+
+/// The system prompt used when neither the caller nor the history provide one.
+pub const DEFAULT_SYSTEM_PROMPT: &str = "\
+You are Ait, a helpful AI assistant. \
+You have extensive knowledge of many facts documented on the world wide web. \
+However, you are not able to perfectly recall those facts. \
+When you are unsure about a detail, do not attempt to provide an answer. \
+You instead state that you do not know the answer.\
+";
+
+/// The values which can be substituted into a system prompt template.
+#[derive(Debug, Clone, Default)]
+pub struct PromptVariables {
+    /// Replaces `{date}`.
+    pub date: Option<String>,
+    /// Replaces `{user_name}`.
+    pub user_name: Option<String>,
+    /// Replaces `{experience_count}`.
+    pub experience_count: Option<usize>,
+}
+
+/// Render a system prompt `template`, substituting the placeholders with the
+/// `variables`. Placeholders whose variable is missing render as `unknown`,
+/// and other text in braces is left unchanged.
+pub fn render_system_prompt(template: &str, variables: &PromptVariables) -> String {
+    let value = |name: &str| -> Option<String> {
+        let value = match name {
+            "date" => variables.date.clone(),
+            "user_name" => variables.user_name.clone(),
+            "experience_count" => variables.experience_count.map(|x| x.to_string()),
+            _ => return None,
+        };
+        Some(value.unwrap_or_else(|| "unknown".to_string()))
+    };
+    // substitute in one pass, so that placeholders in the values are kept
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let substituted = rest
+            .find('}')
+            .and_then(|end| value(&rest[1..end]).map(|x| (end, x)));
+        match substituted {
+            Some((end, value)) => {
+                rendered.push_str(&value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// The tokens kept free for the response when the request doesn't limit it.
+pub const DEFAULT_RESPONSE_TOKENS: usize = 512;
+/// The fewest tokens worth keeping of a response which is trimmed to fit in a
+/// prompt. Experiences which can't keep this many are dropped instead.
+pub const MIN_TRIMMED_RESPONSE_TOKENS: usize = 32;
+
+/// The order in which the experiences of a prompt appear.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExperienceOrder {
+    /// In the order they were experienced, oldest first.
+    #[default]
+    Chronological,
+    MostRelevantFirst,
+    /// Most relevant last, so that it is nearest to the query.
+    MostRelevantLast,
+}
+
+/// A prior experience which can be included in a prompt.
+#[derive(Debug, Clone)]
+pub struct PromptExperience {
+    pub query: String,
+    pub response: String,
+    pub rank: u32,
+}
+
+/// How the tokens of a model's context window are shared.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBudget {
+    /// The most tokens the model can process.
+    pub context_window: usize,
+    /// The tokens kept free for the response.
+    pub response_tokens: usize,
+}
+
+impl TokenBudget {
+    /// The budget of `model`, when its response is limited to `max_tokens`.
+    pub fn for_model(model: &ChatCompletionModel, max_tokens: Option<u32>) -> Self {
+        Self {
+            context_window: model.context_window().unwrap_or(DEFAULT_CONTEXT_WINDOW),
+            response_tokens: max_tokens
+                .map(|x| x as usize)
+                .unwrap_or(DEFAULT_RESPONSE_TOKENS),
+        }
+    }
+
+    /// The most tokens the prompt can use.
+    pub fn prompt_tokens(&self) -> usize {
+        self.context_window.saturating_sub(self.response_tokens)
+    }
+}
+
+/// A prompt built to fit in a token budget.
+#[derive(Debug)]
+pub struct FittedPrompt {
+    pub messages: Vec<ChatCompletionMessage>,
+    /// The indices of the experiences in the prompt, in the order they appear.
+    pub included: Vec<usize>,
+    /// The IDs of the experiences given to a `PromptBuilder`, which `included`
+    /// and `trimmed` index into. Empty for prompts built by `fit_prompt`.
+    pub context: Vec<TextId>,
+    /// The indices of the experiences whose response was trimmed to fit.
+    pub trimmed: Vec<usize>,
+    /// The tokens used by the prompt.
+    pub prompt_tokens: usize,
+    /// The tokens left in the context window for the response.
+    pub remaining_tokens: usize,
+}
+
+/// Build a prompt which asks `query` following the `experiences`, and which
+/// fits in the `budget`.
+///
+/// The `experiences` are ordered from most to least relevant. The most
+/// relevant are kept, the first which doesn't fit has its response trimmed,
+/// and those which still don't fit are dropped. The kept experiences appear in
+/// the given `order`.
+pub fn fit_prompt(
+    counter: &dyn TokenCounter,
+    system_prompt: &str,
+    experiences: &[PromptExperience],
+    query: &str,
+    budget: TokenBudget,
+    order: ExperienceOrder,
+) -> Result<FittedPrompt> {
+    let available = budget.prompt_tokens();
+    let system = ChatCompletionMessage::system(system_prompt);
+    let query = ChatCompletionMessage {
+        role: ChatCompletionMessageRole::User,
+        content: query.to_string(),
+    };
+    let frame = [system, query];
+    let mut used = counter.count_messages(&frame);
+    let [system, query] = frame;
+    if used > available {
+        return Err(Error::PromptTooLong {
+            tokens: used,
+            budget: available,
+        });
+    }
+
+    let mut kept: Vec<(usize, String)> = Vec::new();
+    let mut trimmed: Vec<usize> = Vec::new();
+    for (index, experience) in experiences.iter().enumerate() {
+        let fixed = counter.count(&experience.query) + 2 * MESSAGE_OVERHEAD_TOKENS;
+        let cost = fixed + counter.count(&experience.response);
+        if used + cost <= available {
+            kept.push((index, experience.response.clone()));
+            used += cost;
+            continue;
+        }
+        let left = available.saturating_sub(used);
+        if trimmed.is_empty() && left >= fixed + MIN_TRIMMED_RESPONSE_TOKENS {
+            let response = counter.truncate(&experience.response, left - fixed);
+            used += fixed + counter.count(&response);
+            kept.push((index, response));
+            trimmed.push(index);
+        }
+    }
+    match order {
+        ExperienceOrder::Chronological => kept.sort_by_key(|(index, _)| experiences[*index].rank),
+        ExperienceOrder::MostRelevantFirst => kept.sort_by_key(|(index, _)| *index),
+        ExperienceOrder::MostRelevantLast => kept.sort_by_key(|(index, _)| usize::MAX - index),
+    }
+
+    let mut messages = vec![system];
+    for (index, response) in &kept {
+        messages.push(ChatCompletionMessage {
+            role: ChatCompletionMessageRole::User,
+            content: experiences[*index].query.clone(),
+        });
+        messages.push(ChatCompletionMessage {
+            role: ChatCompletionMessageRole::Assistant,
+            content: response.clone(),
+        });
+    }
+    messages.push(query);
+    let prompt_tokens = counter.count_messages(&messages);
+    Ok(FittedPrompt {
+        messages,
+        included: kept.into_iter().map(|(index, _)| index).collect(),
+        context: Vec::new(),
+        trimmed,
+        prompt_tokens,
+        remaining_tokens: budget.context_window.saturating_sub(prompt_tokens),
+    })
+}
+
+/// How a `PromptBuilder` builds prompts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PromptOptions {
+    pub order: ExperienceOrder,
+    /// The most experiences to include, before fitting them in the budget.
+    pub max_experiences: usize,
+    /// How `PromptBuilder::build` finds the related experiences.
+    pub related: RelatedOptions,
+    /// The model which is prompted. It determines how tokens are counted, and
+    /// the size of the context window.
+    pub model: ChatCompletionModel,
+    /// Use this context window instead of the model's.
+    pub context_window: Option<usize>,
+    /// The tokens kept free for the response.
+    pub response_tokens: Option<u32>,
+    /// The system prompt template. The history's persona, or the default
+    /// system prompt, are used when `None`.
+    pub system_prompt: Option<String>,
+    /// Substituted for `{date}` in the system prompt.
+    pub date: Option<String>,
+    /// Substituted for `{user_name}` in the system prompt.
+    pub user_name: Option<String>,
+}
+
+impl Default for PromptOptions {
+    fn default() -> Self {
+        Self {
+            order: ExperienceOrder::default(),
+            max_experiences: 128,
+            related: RelatedOptions::default(),
+            model: ChatCompletionModel::default(),
+            context_window: None,
+            response_tokens: Some(2048),
+            system_prompt: None,
+            date: None,
+            user_name: None,
+        }
+    }
+}
+
+impl PromptOptions {
+    pub fn budget(&self) -> TokenBudget {
+        let mut budget = TokenBudget::for_model(&self.model, self.response_tokens);
+        if let Some(context_window) = self.context_window {
+            budget.context_window = context_window;
+        }
+        budget
+    }
+}
+
+/// Builds the prompts which query a model with the experiences of a history.
+pub struct PromptBuilder<'a, const N: usize> {
+    history: &'a History<N>,
+    options: PromptOptions,
+    counter: Box<dyn TokenCounter>,
+}
+
+impl<'a, const N: usize> PromptBuilder<'a, N> {
+    pub fn new(history: &'a History<N>, options: PromptOptions) -> Self {
+        let counter = token_counter(&options.model);
+        Self {
+            history,
+            options,
+            counter,
+        }
+    }
+
+    /// Count tokens with `counter` instead of the model's tokenizer.
+    pub fn with_counter(mut self, counter: Box<dyn TokenCounter>) -> Self {
+        self.counter = counter;
+        self
+    }
+
+    /// The rendered system prompt.
+    pub fn system_prompt(&self) -> String {
+        let template = self
+            .options
+            .system_prompt
+            .as_deref()
+            .or_else(|| self.history.persona())
+            .unwrap_or(DEFAULT_SYSTEM_PROMPT);
+        let variables = PromptVariables {
+            date: self.options.date.clone(),
+            user_name: self.options.user_name.clone(),
+            experience_count: Some(self.history.len()),
+        };
+        render_system_prompt(template, &variables)
+    }
+
+    /// Build a prompt for `query`, with the experiences most related to its
+    /// `embedding`.
+    pub fn build(&self, query: &str, embedding: &Embedding<N>) -> Result<FittedPrompt> {
+        let ids: Vec<TextId> = self
+            .history
+            .related_with(
+                embedding,
+                self.options.max_experiences,
+                &self.options.related,
+            )?
+            .into_iter()
+            .map(|x| x.id)
+            .collect();
+        self.build_with_context(query, &ids)
+    }
+
+    /// Build a prompt for `query`, with the experiences of `context_ids`,
+    /// ordered from most to least relevant. Unknown ids are skipped.
+    pub fn build_with_context(&self, query: &str, context_ids: &[TextId]) -> Result<FittedPrompt> {
+        let (context, experiences): (Vec<TextId>, Vec<PromptExperience>) = context_ids
+            .iter()
+            .filter_map(|x| self.history.get(x))
+            .take(self.options.max_experiences)
+            .map(|x| {
+                let experience = PromptExperience {
+                    query: x.query.clone(),
+                    response: x.response.clone(),
+                    rank: x.rank,
+                };
+                (x.id, experience)
+            })
+            .unzip();
+        let mut prompt = fit_prompt(
+            self.counter.as_ref(),
+            &self.system_prompt(),
+            &experiences,
+            query,
+            self.options.budget(),
+            self.options.order,
+        )?;
+        prompt.context = context;
+        Ok(prompt)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::history::Search;
+    use crate::tokens::{ApproximateTokenCounter, REPLY_OVERHEAD_TOKENS};
+
+    fn experience(query: &str, response_chars: usize, rank: u32) -> PromptExperience {
+        PromptExperience {
+            query: query.to_string(),
+            response: "r".repeat(response_chars),
+            rank,
+        }
+    }
+
+    #[test]
+    fn fits_prompt_in_budget() {
+        let counter = ApproximateTokenCounter;
+        // each experience costs 1 + 2 * 4 tokens, plus 1 per 4 response chars
+        let experiences = vec![
+            experience("q1", 4 * 10, 3),
+            experience("q2", 4 * 100, 1),
+            experience("q3", 4 * 10, 2),
+            experience("q4", 4 * 100, 0),
+        ];
+        // system and query cost 1 + 4 each, plus 3 to prime the reply
+        let fixed = 2 * (1 + MESSAGE_OVERHEAD_TOKENS) + REPLY_OVERHEAD_TOKENS;
+        let budget = TokenBudget {
+            context_window: fixed + (9 + 10) + (9 + 40) + 100,
+            response_tokens: 100,
+        };
+        let prompt = fit_prompt(
+            &counter,
+            "s",
+            &experiences,
+            "q",
+            budget,
+            ExperienceOrder::Chronological,
+        )
+        .unwrap();
+        // q1 fits, q2 is trimmed to 40 tokens, q3 and q4 are dropped
+        assert_eq!(prompt.included, vec![1, 0]);
+        assert_eq!(prompt.trimmed, vec![1]);
+        assert_eq!(prompt.messages.len(), 2 + 2 * 2);
+        assert_eq!(prompt.messages[1].content, "q2");
+        assert_eq!(prompt.messages[2].content.len(), 4 * 40);
+        assert_eq!(prompt.messages[3].content, "q1");
+        assert_eq!(prompt.messages[5].content, "q");
+        assert_eq!(prompt.prompt_tokens, budget.prompt_tokens());
+        assert_eq!(prompt.remaining_tokens, 100);
+
+        let budget = TokenBudget {
+            context_window: fixed + (9 + 10) + (9 + 10) + 20 + 100,
+            response_tokens: 100,
+        };
+        let prompt = fit_prompt(
+            &counter,
+            "s",
+            &experiences,
+            "q",
+            budget,
+            ExperienceOrder::Chronological,
+        )
+        .unwrap();
+        // q1 fits, q2 is too long to trim, q3 fits, q4 is too long to trim
+        assert_eq!(prompt.included, vec![2, 0]);
+        assert!(prompt.trimmed.is_empty());
+        assert_eq!(prompt.remaining_tokens, 100 + 20);
+
+        let budget = TokenBudget {
+            context_window: fixed + 100 - 1,
+            response_tokens: 100,
+        };
+        assert!(matches!(
+            fit_prompt(
+                &counter,
+                "s",
+                &experiences,
+                "q",
+                budget,
+                ExperienceOrder::Chronological
+            ),
+            Err(Error::PromptTooLong { .. })
+        ));
+    }
+
+    #[test]
+    fn builds_prompt_from_history() {
+        let mut history = History::<2>::new();
+        let id1 = history
+            .push("q1", "r1", Embedding::new("", [0.0, 1.0]), vec![])
+            .unwrap();
+        let id2 = history
+            .push("q2", "r2", Embedding::new("", [1.0, 0.0]), vec![id1])
+            .unwrap();
+        history.set_persona(Some("Persona with {experience_count}.".to_string()));
+        let options = PromptOptions {
+            order: ExperienceOrder::MostRelevantLast,
+            ..Default::default()
+        };
+        let builder = PromptBuilder::new(&history, options);
+        let prompt = builder.build("q", &Embedding::new("", [0.1, 1.0])).unwrap();
+        let contents: Vec<&str> = prompt.messages.iter().map(|x| x.content.as_str()).collect();
+        assert_eq!(
+            contents,
+            vec!["Persona with 2.", "q2", "r2", "q1", "r1", "q"]
+        );
+
+        let options = PromptOptions {
+            max_experiences: 1,
+            system_prompt: Some("System.".to_string()),
+            ..Default::default()
+        };
+        let builder = PromptBuilder::new(&history, options);
+        let prompt = builder.build_with_context("q", &[id2, id1]).unwrap();
+        let contents: Vec<&str> = prompt.messages.iter().map(|x| x.content.as_str()).collect();
+        assert_eq!(contents, vec!["System.", "q2", "r2", "q"]);
+        assert_eq!(prompt.context, vec![id2]);
+        assert_eq!(prompt.included, vec![0]);
+
+        let options = PromptOptions {
+            related: RelatedOptions {
+                search: Search::Exhaustive,
+                max_distance: Some(0.5),
+                ..Default::default()
+            },
+            system_prompt: Some("System.".to_string()),
+            ..Default::default()
+        };
+        let builder = PromptBuilder::new(&history, options);
+        let prompt = builder.build("q", &Embedding::new("", [0.1, 1.0])).unwrap();
+        let contents: Vec<&str> = prompt.messages.iter().map(|x| x.content.as_str()).collect();
+        assert_eq!(contents, vec!["System.", "q1", "r1", "q"]);
+    }
+
+    #[test]
+    fn renders_system_prompt() {
+        let variables = PromptVariables {
+            date: Some("2023-04-01".to_string()),
+            user_name: None,
+            experience_count: Some(3),
+        };
+        assert_eq!(
+            render_system_prompt(
+                "On {date}, {user_name} asks with {experience_count} {memories}.",
+                &variables
+            ),
+            "On 2023-04-01, unknown asks with 3 {memories}."
+        );
+        assert_eq!(
+            render_system_prompt(DEFAULT_SYSTEM_PROMPT, &variables),
+            DEFAULT_SYSTEM_PROMPT
+        );
+        let variables = PromptVariables {
+            date: Some("2023-04-01".to_string()),
+            user_name: Some("{date}".to_string()),
+            experience_count: None,
+        };
+        assert_eq!(
+            render_system_prompt("{{user_name}} on {date}{", &variables),
+            "{{date}} on 2023-04-01{"
+        );
+    }
+}
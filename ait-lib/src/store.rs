@@ -0,0 +1,581 @@
+#[cfg(feature = "native")]
+use std::fs::{self, File, OpenOptions};
+#[cfg(feature = "native")]
+use std::io::Write;
+#[cfg(feature = "native")]
+use std::path::{Path, PathBuf};
+
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
+
+use crate::history::{History, HistoryMetadata, LinkedExperience};
+use crate::hnsw::Node;
+
+/// The version of the format written by `StoreFormat`. Increment it when the
+/// format changes, and add readers for the new version to the registries of
+/// `StoreFormat::deserialize` and `StoreFormat::deserialize_records`.
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("the store isn't available")]
+    Unavailable,
+    #[error("failed to serialize the history: {0}")]
+    CantSerialize(String),
+    #[error("failed to deserialize the history: {0}")]
+    CantDeserialize(String),
+    #[error("the store failed: {0}")]
+    Failed(String),
+    #[error("the history has format version {version}, but only versions up to {FORMAT_VERSION} are supported")]
+    UnsupportedVersion { version: u32 },
+    #[error("the history has embeddings with {dims} dimensions, but {expected} were expected")]
+    IncompatibleEmbedding { dims: usize, expected: usize },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+type Result<T> = core::result::Result<T, Error>;
+
+/// How a history is encoded in a store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StoreFormat {
+    /// Compact, and the format of the browser's storage.
+    #[default]
+    MessagePack,
+    /// Readable and editable, with text IDs in hexadecimal.
+    Json,
+}
+
+/// Wraps a stored history with what is needed to read it back.
+#[derive(Serialize, Deserialize)]
+struct Envelope<H> {
+    version: u32,
+    embedding_model: Option<String>,
+    dims: usize,
+    created_at: Option<u64>,
+    history: H,
+}
+
+/// Reads a history stored in one version of the format.
+type Reader<const N: usize> = fn(StoreFormat, &[u8]) -> Result<History<N>>;
+
+/// Before version 1, the history was stored without an envelope.
+fn read_v0<const N: usize>(format: StoreFormat, data: &[u8]) -> Result<History<N>> {
+    format.decode(data)
+}
+
+fn read_v1<const N: usize>(format: StoreFormat, data: &[u8]) -> Result<History<N>> {
+    let envelope: Envelope<History<N>> = format.decode(data)?;
+    let mut history = envelope.history;
+    history.set_embedding_model(envelope.embedding_model);
+    history.set_created_at(envelope.created_at);
+    Ok(history)
+}
+
+/// Reads a history kept as separate records, as written by
+/// `StoreFormat::serialize_metadata` and `StoreFormat::serialize_experience`,
+/// in one version of the format.
+type RecordsReader<const N: usize> = fn(StoreFormat, &[u8], &[Vec<u8>]) -> Result<History<N>>;
+
+/// The record of an experience, with its node in the index if there is one.
+#[derive(Serialize)]
+struct ExperienceRecord<'a, const N: usize> {
+    #[serde(flatten)]
+    experience: &'a LinkedExperience<N>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    node: Option<&'a Node>,
+}
+
+/// The node of an experience record, read apart from the experience since
+/// flattened fields lose the human readability of the format, which text IDs
+/// depend on.
+#[derive(Deserialize)]
+struct StoredNode {
+    #[serde(default)]
+    node: Option<Node>,
+}
+
+fn read_experiences<const N: usize>(
+    format: StoreFormat,
+    experiences: &[Vec<u8>],
+) -> Result<Vec<(LinkedExperience<N>, Option<Node>)>> {
+    experiences
+        .iter()
+        .map(|x| {
+            let stored: StoredNode = format.decode(x)?;
+            Ok((format.decode(x)?, stored.node))
+        })
+        .collect()
+}
+
+/// Before version 1, the metadata record was stored without an envelope.
+fn read_records_v0<const N: usize>(
+    format: StoreFormat,
+    metadata: &[u8],
+    experiences: &[Vec<u8>],
+) -> Result<History<N>> {
+    let metadata: HistoryMetadata = format.decode(metadata)?;
+    // and the experiences without nodes, sometimes positionally
+    let experiences = experiences
+        .iter()
+        .map(|x| format.decode(x))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(History::from_parts(metadata, experiences))
+}
+
+fn read_records_v1<const N: usize>(
+    format: StoreFormat,
+    metadata: &[u8],
+    experiences: &[Vec<u8>],
+) -> Result<History<N>> {
+    let envelope: Envelope<HistoryMetadata> = format.decode(metadata)?;
+    let metadata = HistoryMetadata {
+        embedding_model: envelope.embedding_model,
+        created_at: envelope.created_at,
+        ..envelope.history
+    };
+    Ok(History::from_records(
+        metadata,
+        read_experiences(format, experiences)?,
+    ))
+}
+
+impl StoreFormat {
+    pub fn serialize<const N: usize>(&self, history: &History<N>) -> Result<Vec<u8>> {
+        self.encode(&Envelope {
+            version: FORMAT_VERSION,
+            embedding_model: history.embedding_model().map(str::to_string),
+            dims: N,
+            created_at: history.created_at(),
+            history,
+        })
+    }
+
+    /// Read a history stored in any version of the format, upgrading it to
+    /// the current version.
+    pub fn deserialize<const N: usize>(&self, data: &[u8]) -> Result<History<N>> {
+        // The index of each reader is the version it reads.
+        let readers: [Reader<N>; FORMAT_VERSION as usize + 1] = [read_v0::<N>, read_v1::<N>];
+        let version = self.version::<N>(data)?;
+        let reader = readers
+            .get(version as usize)
+            .ok_or(Error::UnsupportedVersion { version })?;
+        reader(*self, data)
+    }
+
+    /// Encode the metadata of a history kept as a record per experience, in
+    /// the envelope which `serialize` writes around a whole history.
+    pub fn serialize_metadata<const N: usize>(
+        &self,
+        metadata: &HistoryMetadata,
+    ) -> Result<Vec<u8>> {
+        self.encode(&Envelope {
+            version: FORMAT_VERSION,
+            embedding_model: metadata.embedding_model.clone(),
+            dims: N,
+            created_at: metadata.created_at,
+            history: metadata,
+        })
+    }
+
+    /// Encode the record of an experience, with its `node` in the index.
+    /// Its version is that of the metadata record it is stored with.
+    pub fn serialize_experience<const N: usize>(
+        &self,
+        experience: &LinkedExperience<N>,
+        node: Option<&Node>,
+    ) -> Result<Vec<u8>> {
+        self.encode(&ExperienceRecord { experience, node })
+    }
+
+    /// Read a history kept as a `metadata` record and a record per
+    /// experience, in any version of the format.
+    pub fn deserialize_records<const N: usize>(
+        &self,
+        metadata: &[u8],
+        experiences: &[Vec<u8>],
+    ) -> Result<History<N>> {
+        // The index of each reader is the version it reads.
+        let readers: [RecordsReader<N>; FORMAT_VERSION as usize + 1] =
+            [read_records_v0::<N>, read_records_v1::<N>];
+        let version = self.version::<N>(metadata)?;
+        let reader = readers
+            .get(version as usize)
+            .ok_or(Error::UnsupportedVersion { version })?;
+        reader(*self, metadata, experiences)
+    }
+
+    /// The version of the format of `data`, which is 0 without an envelope.
+    fn version<const N: usize>(&self, data: &[u8]) -> Result<u32> {
+        let version = match self.decode::<Envelope<IgnoredAny>>(data) {
+            Ok(envelope) => {
+                if envelope.dims != N {
+                    return Err(Error::IncompatibleEmbedding {
+                        dims: envelope.dims,
+                        expected: N,
+                    });
+                }
+                envelope.version
+            }
+            Err(_) => 0,
+        };
+        Ok(version)
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            StoreFormat::MessagePack => {
+                rmp_serde::to_vec_named(value).map_err(|e| Error::CantSerialize(e.to_string()))
+            }
+            StoreFormat::Json => {
+                serde_json::to_vec(value).map_err(|e| Error::CantSerialize(e.to_string()))
+            }
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        match self {
+            StoreFormat::MessagePack => {
+                rmp_serde::from_slice(data).map_err(|e| Error::CantDeserialize(e.to_string()))
+            }
+            StoreFormat::Json => {
+                serde_json::from_slice(data).map_err(|e| Error::CantDeserialize(e.to_string()))
+            }
+        }
+    }
+}
+
+/// Somewhere a history is kept between sessions.
+pub trait HistoryStore<const N: usize> {
+    /// Read the stored history, or `None` if no history was stored.
+    fn load(&self) -> Result<Option<History<N>>>;
+
+    /// Replace the stored history with `history`.
+    fn store(&self, history: &History<N>) -> Result<()>;
+
+    /// Read the stored history, or a new history if none was stored.
+    fn load_or_default(&self) -> Result<History<N>> {
+        Ok(self.load()?.unwrap_or_default())
+    }
+}
+
+/// Keeps a history in a file.
+///
+/// Writes go to a temporary file which then replaces the history, so that
+/// readers never see a partial write. Reads and writes take a lock on a file
+/// next to the history, so that processes sharing the history don't
+/// interleave their writes. The lock is released in between, so a history
+/// which is loaded, modified and stored should be handled through
+/// `FileStore::lock`, which keeps other processes from updating it meanwhile.
+#[cfg(feature = "native")]
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    path: PathBuf,
+    format: StoreFormat,
+}
+
+#[cfg(feature = "native")]
+impl FileStore {
+    /// Keep a history at `path`, as JSON if its extension is `json`, and as
+    /// MessagePack otherwise.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let format = match path.extension() {
+            Some(x) if x.eq_ignore_ascii_case("json") => StoreFormat::Json,
+            _ => StoreFormat::MessagePack,
+        };
+        Self { path, format }
+    }
+
+    pub fn with_format(path: impl Into<PathBuf>, format: StoreFormat) -> Self {
+        Self {
+            path: path.into(),
+            format,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn format(&self) -> StoreFormat {
+        self.format
+    }
+
+    /// The path of `path` with `suffix` appended to its file name.
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(suffix);
+        self.path.with_file_name(name)
+    }
+
+    /// Replace the stored history with `data`, a history encoded in this
+    /// store's format. This lets callers encode the history while it is
+    /// borrowed, and write it elsewhere.
+    pub fn write(&self, data: &[u8]) -> Result<()> {
+        let lock = self.lock_file()?;
+        lock.lock()?;
+        self.replace(data)
+    }
+
+    /// Lock the history until the returned lock is dropped, so that other
+    /// processes can't read or write it meanwhile. The history is then
+    /// loaded and stored through the lock.
+    pub fn lock(&self) -> Result<FileStoreLock<'_>> {
+        let file = self.lock_file()?;
+        file.lock()?;
+        Ok(FileStoreLock {
+            store: self,
+            _file: file,
+        })
+    }
+
+    /// Open the lock file, which is unlocked when it is closed.
+    fn lock_file(&self) -> Result<File> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.sibling(".lock"))?;
+        Ok(file)
+    }
+
+    /// Read the stored data, without taking the lock.
+    fn read(&self) -> Result<Option<Vec<u8>>> {
+        match fs::read(&self.path) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replace the stored data, without taking the lock.
+    fn replace(&self, data: &[u8]) -> Result<()> {
+        let temp_path = self.sibling(".tmp");
+        let mut temp = File::create(&temp_path)?;
+        temp.write_all(data)?;
+        temp.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "native")]
+impl<const N: usize> HistoryStore<N> for FileStore {
+    fn load(&self) -> Result<Option<History<N>>> {
+        let lock = self.lock_file()?;
+        lock.lock_shared()?;
+        self.read()?
+            .map(|x| self.format.deserialize(&x))
+            .transpose()
+    }
+
+    fn store(&self, history: &History<N>) -> Result<()> {
+        let data = self.format.serialize(history)?;
+        self.write(&data)
+    }
+}
+
+/// An exclusive lock on the history of a `FileStore`, taken by
+/// `FileStore::lock`.
+#[cfg(feature = "native")]
+#[derive(Debug)]
+pub struct FileStoreLock<'a> {
+    store: &'a FileStore,
+    _file: File,
+}
+
+#[cfg(feature = "native")]
+impl FileStoreLock<'_> {
+    /// Like `FileStore::write`, while holding the lock.
+    pub fn write(&self, data: &[u8]) -> Result<()> {
+        self.store.replace(data)
+    }
+}
+
+#[cfg(feature = "native")]
+impl<const N: usize> HistoryStore<N> for FileStoreLock<'_> {
+    fn load(&self) -> Result<Option<History<N>>> {
+        self.store
+            .read()?
+            .map(|x| self.store.format.deserialize(&x))
+            .transpose()
+    }
+
+    fn store(&self, history: &History<N>) -> Result<()> {
+        let data = self.store.format.serialize(history)?;
+        self.write(&data)
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod test {
+    use super::*;
+    use crate::embedding::Embedding;
+    use crate::utils::text_id_to_hex;
+
+    #[test]
+    fn file_store_stores_and_loads() {
+        let dir = std::env::temp_dir().join(format!("ait_store_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut history = History::<2>::new();
+        let id1 = history
+            .push("q1", "r1", Embedding::new("", [0.0, 1.0]), vec![])
+            .unwrap();
+        let id2 = history
+            .push("q2", "r2", Embedding::new("", [1.0, 0.0]), vec![id1])
+            .unwrap();
+
+        for name in ["history", "history.json"] {
+            let store = FileStore::new(dir.join(name));
+            assert!(HistoryStore::<2>::load(&store).unwrap().is_none());
+            store.store(&history).unwrap();
+            let loaded: History<2> = store.load().unwrap().unwrap();
+            assert_eq!(loaded.get(&id2).unwrap().query, "q2");
+            assert_eq!(loaded.links(&id2).unwrap(), [id1]);
+            assert!(!store.sibling(".tmp").exists());
+        }
+
+        let json = fs::read_to_string(dir.join("history.json")).unwrap();
+        assert!(json.contains(&format!("\"{}\"", text_id_to_hex(&id1))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn file_store_lock_serializes_updates() {
+        let dir = std::env::temp_dir().join(format!("ait_store_lock_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let store = FileStore::new(dir.join("history"));
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    let lock = store.lock().unwrap();
+                    let mut history: History<2> = lock.load_or_default().unwrap();
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                    let query = i.to_string();
+                    let embedding = Embedding::new("", [1.0, 0.0]);
+                    history.push(&query, "r", embedding, vec![]).unwrap();
+                    lock.store(&history).unwrap();
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let history: History<2> = store.load().unwrap().unwrap();
+        assert_eq!(history.len(), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod format_test {
+    use super::*;
+    use crate::embedding::Embedding;
+
+    #[test]
+    fn formats_keep_the_envelope() {
+        let mut history = History::<2>::new();
+        history.set_embedding_model(Some("model".to_string()));
+        history
+            .push("q", "r", Embedding::new("", [0.0, 1.0]), vec![])
+            .unwrap();
+        for format in [StoreFormat::MessagePack, StoreFormat::Json] {
+            let data = format.serialize(&history).unwrap();
+            let loaded: History<2> = format.deserialize(&data).unwrap();
+            assert_eq!(loaded.len(), 1);
+            assert_eq!(loaded.embedding_model(), Some("model"));
+            assert_eq!(loaded.created_at(), history.created_at());
+        }
+    }
+
+    #[test]
+    fn formats_read_histories_without_an_envelope() {
+        let mut history = History::<2>::new();
+        history
+            .push("q", "r", Embedding::new("", [0.0, 1.0]), vec![])
+            .unwrap();
+        let data = rmp_serde::to_vec(&history).unwrap();
+        let loaded: History<2> = StoreFormat::MessagePack.deserialize(&data).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded.created_at(), None);
+        let data = serde_json::to_vec(&history).unwrap();
+        let loaded: History<2> = StoreFormat::Json.deserialize(&data).unwrap();
+        assert_eq!(loaded.len(), 1);
+    }
+
+    #[test]
+    fn formats_read_records() {
+        let mut history = History::<2>::new();
+        history.set_embedding_model(Some("model".to_string()));
+        let id = history
+            .push("q", "r", Embedding::new("", [0.0, 1.0]), vec![])
+            .unwrap();
+        let format = StoreFormat::MessagePack;
+        let experience = format
+            .serialize_experience(history.linked(&id).unwrap(), None)
+            .unwrap();
+        let metadata = format.serialize_metadata::<2>(&history.metadata()).unwrap();
+        let loaded: History<2> = format
+            .deserialize_records(&metadata, &[experience])
+            .unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded.embedding_model(), Some("model"));
+        assert!(matches!(
+            format.deserialize_records::<3>(&metadata, &[]),
+            Err(Error::IncompatibleEmbedding { dims: 2, .. })
+        ));
+
+        // records were first stored without an envelope, and positionally
+        let experience = rmp_serde::to_vec(history.linked(&id).unwrap()).unwrap();
+        let metadata = rmp_serde::to_vec(&history.metadata()).unwrap();
+        let loaded: History<2> = format
+            .deserialize_records(&metadata, &[experience])
+            .unwrap();
+        assert_eq!(loaded.get(&id).unwrap().response, "r");
+
+        // the nodes of the index are kept with the experiences
+        history.set_index(Some(Default::default()));
+        for format in [StoreFormat::MessagePack, StoreFormat::Json] {
+            let experience = format
+                .serialize_experience(history.linked(&id).unwrap(), history.index_node(&id))
+                .unwrap();
+            let metadata = format.serialize_metadata::<2>(&history.metadata()).unwrap();
+            let mut loaded: History<2> = format
+                .deserialize_records(&metadata, &[experience])
+                .unwrap();
+            assert_eq!(loaded.index_node(&id), history.index_node(&id));
+            assert_eq!(loaded.index().unwrap().entry(), Some(id));
+            assert!(loaded.take_changes().is_empty());
+        }
+    }
+
+    #[test]
+    fn formats_reject_unknown_versions_and_dimensions() {
+        let history = History::<2>::new();
+        let mut envelope = serde_json::to_value(Envelope {
+            version: FORMAT_VERSION + 1,
+            embedding_model: None,
+            dims: 2,
+            created_at: None,
+            history: &history,
+        })
+        .unwrap();
+        let data = serde_json::to_vec(&envelope).unwrap();
+        assert!(matches!(
+            StoreFormat::Json.deserialize::<2>(&data),
+            Err(Error::UnsupportedVersion { version }) if version == FORMAT_VERSION + 1
+        ));
+        envelope["version"] = FORMAT_VERSION.into();
+        envelope["dims"] = 3.into();
+        let data = serde_json::to_vec(&envelope).unwrap();
+        assert!(matches!(
+            StoreFormat::Json.deserialize::<2>(&data),
+            Err(Error::IncompatibleEmbedding {
+                dims: 3,
+                expected: 2
+            })
+        ));
+    }
+}
@@ -0,0 +1,118 @@
+//! Count the tokens in prompts, to fit them in a model's context window.
+
+use crate::gpt::{ChatCompletionMessage, ChatCompletionModel};
+
+/// The tokens used by each message of a chat, in addition to its content.
+pub const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+/// The tokens used to prime the model's reply to a chat.
+pub const REPLY_OVERHEAD_TOKENS: usize = 3;
+/// The context window assumed for models not known to this crate.
+pub const DEFAULT_CONTEXT_WINDOW: usize = 4096;
+
+/// Counts the tokens in text, as seen by some model.
+pub trait TokenCounter {
+    fn count(&self, text: &str) -> usize;
+
+    /// Keep only the leading tokens of `text`, up to `max_tokens` of them.
+    fn truncate(&self, text: &str, max_tokens: usize) -> String;
+
+    /// The tokens used by a chat made of `messages`, including the tokens which
+    /// prime the model's reply.
+    fn count_messages(&self, messages: &[ChatCompletionMessage]) -> usize {
+        messages
+            .iter()
+            .map(|x| self.count(&x.content) + MESSAGE_OVERHEAD_TOKENS)
+            .sum::<usize>()
+            + REPLY_OVERHEAD_TOKENS
+    }
+}
+
+/// Estimates tokens from the text length, assuming about 4 characters per
+/// token as is typical of English text. Used for models whose tokenizer isn't
+/// known.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ApproximateTokenCounter;
+
+const CHARS_PER_TOKEN: usize = 4;
+
+impl TokenCounter for ApproximateTokenCounter {
+    fn count(&self, text: &str) -> usize {
+        text.chars().count().div_ceil(CHARS_PER_TOKEN)
+    }
+
+    fn truncate(&self, text: &str, max_tokens: usize) -> String {
+        text.chars()
+            .take(max_tokens.saturating_mul(CHARS_PER_TOKEN))
+            .collect()
+    }
+}
+
+/// Counts tokens with the `cl100k_base` encoding used by GPT-3.5 and GPT-4.
+#[cfg(feature = "tiktoken")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cl100kTokenCounter;
+
+#[cfg(feature = "tiktoken")]
+impl TokenCounter for Cl100kTokenCounter {
+    fn count(&self, text: &str) -> usize {
+        tiktoken_rs::cl100k_base_singleton()
+            .lock()
+            .encode_with_special_tokens(text)
+            .len()
+    }
+
+    fn truncate(&self, text: &str, max_tokens: usize) -> String {
+        let bpe = tiktoken_rs::cl100k_base_singleton();
+        let bpe = bpe.lock();
+        let tokens = bpe.encode_with_special_tokens(text);
+        if tokens.len() <= max_tokens {
+            return text.to_string();
+        }
+        // a character can span many tokens, so cutting at `max_tokens` can
+        // leave a partial character which doesn't decode
+        (0..=max_tokens)
+            .rev()
+            .find_map(|x| bpe.decode(tokens[..x].to_vec()).ok())
+            .unwrap_or_default()
+    }
+}
+
+/// The token counter which best matches the `model`'s tokenizer.
+pub fn token_counter(model: &ChatCompletionModel) -> Box<dyn TokenCounter> {
+    match model {
+        #[cfg(feature = "tiktoken")]
+        ChatCompletionModel::Gpt35Turbo
+        | ChatCompletionModel::Gpt35Turbo0301
+        | ChatCompletionModel::Gpt4
+        | ChatCompletionModel::Gpt4_32k => Box::new(Cl100kTokenCounter),
+        _ => Box::new(ApproximateTokenCounter),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn approximate_counter_counts_and_truncates() {
+        let counter = ApproximateTokenCounter;
+        assert_eq!(counter.count(""), 0);
+        assert_eq!(counter.count("hello"), 2);
+        assert_eq!(counter.truncate("hello world", 2), "hello wo");
+        assert_eq!(counter.truncate("hello world", usize::MAX), "hello world");
+    }
+
+    #[cfg(feature = "tiktoken")]
+    #[test]
+    fn cl100k_counter_counts_and_truncates() {
+        let counter = Cl100kTokenCounter;
+        assert_eq!(counter.count("hello world"), 2);
+        assert_eq!(counter.truncate("hello world", 1), "hello");
+        assert_eq!(counter.truncate("hello world", 5), "hello world");
+        let messages = vec![ChatCompletionMessage::system("hello world")];
+        assert_eq!(
+            counter.count_messages(&messages),
+            2 + MESSAGE_OVERHEAD_TOKENS + REPLY_OVERHEAD_TOKENS
+        );
+    }
+}
@@ -1,8 +1,35 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_with::{DeserializeAs, SerializeAs};
 use sha2::{Digest, Sha256};
-use web_sys::console;
+use std::time::Duration;
 
 pub type TextId = [u8; 32];
 
+/// Serializes a `TextId` as hexadecimal in human readable formats (e.g. JSON),
+/// and as its bytes otherwise. Use with `#[serde_as(as = "TextIdHex")]`.
+pub struct TextIdHex;
+
+impl SerializeAs<TextId> for TextIdHex {
+    fn serialize_as<S: Serializer>(source: &TextId, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&text_id_to_hex(source))
+        } else {
+            source.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> DeserializeAs<'de, TextId> for TextIdHex {
+    fn deserialize_as<D: Deserializer<'de>>(deserializer: D) -> Result<TextId, D::Error> {
+        if deserializer.is_human_readable() {
+            let hex = String::deserialize(deserializer)?;
+            text_id_from_hex(&hex).ok_or_else(|| de::Error::custom("invalid text ID"))
+        } else {
+            TextId::deserialize(deserializer)
+        }
+    }
+}
+
 pub fn new_text_id<TList: AsRef<[TStr]>, TStr: AsRef<str>>(texts: &TList) -> TextId {
     let mut hash = Sha256::new();
     for text in texts.as_ref() {
@@ -11,7 +38,76 @@ pub fn new_text_id<TList: AsRef<[TStr]>, TStr: AsRef<str>>(texts: &TList) -> Tex
     hash.finalize().into()
 }
 
-#[allow(dead_code)]
+/// The lowercase hexadecimal representation of `text_id`.
+pub fn text_id_to_hex(text_id: &TextId) -> String {
+    text_id.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Parse a text ID from its hexadecimal representation.
+pub fn text_id_from_hex(hex: &str) -> Option<TextId> {
+    if hex.len() != 64 || !hex.bytes().all(|x| x.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut text_id = [0u8; 32];
+    for (byte, digits) in text_id.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).ok()?;
+        *byte = u8::from_str_radix(digits, 16).ok()?;
+    }
+    Some(text_id)
+}
+
+/// Log `text` to the browser console.
+#[cfg(feature = "wasm")]
 pub fn console_log(text: &str) {
-    console::log_1(&text.into());
+    web_sys::console::log_1(&text.into());
+}
+
+/// Wait for `duration` without blocking the thread.
+#[cfg(all(feature = "wasm", not(feature = "native")))]
+pub async fn sleep(duration: Duration) {
+    let millis = duration.as_millis().min(u32::MAX as u128) as u32;
+    gloo_timers::future::TimeoutFuture::new(millis).await;
+}
+
+/// Wait for `duration` without blocking the thread.
+#[cfg(feature = "native")]
+pub async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await;
+}
+
+/// The current time, in seconds since the Unix epoch.
+#[cfg(target_arch = "wasm32")]
+pub fn now() -> u64 {
+    (js_sys::Date::now() / 1000.0) as u64
+}
+
+/// The current time, in seconds since the Unix epoch.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn now() -> u64 {
+    use std::time::SystemTime;
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+/// A number drawn uniformly from `[0, 1)`. Not suitable for cryptography.
+#[cfg(target_arch = "wasm32")]
+pub fn random() -> f64 {
+    js_sys::Math::random()
+}
+
+/// A number drawn uniformly from `[0, 1)`. Not suitable for cryptography.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn random() -> f64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    use std::time::SystemTime;
+    let mut hasher = RandomState::new().build_hasher();
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|x| x.as_nanos())
+        .unwrap_or_default();
+    hasher.write_u128(nanos);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
 }
@@ -11,3 +11,14 @@ wasm_bindgen_test_configure!(run_in_browser);
 fn pass() {
     assert!(true);
 }
+
+#[wasm_bindgen_test]
+async fn history_saves_to_indexed_db() {
+    let mut history = ait_lib::History::replace(None).await.unwrap();
+    history.set_persona(Some("A persona".to_string()));
+    wasm_bindgen_futures::JsFuture::from(history.save())
+        .await
+        .unwrap();
+    let history = ait_lib::History::open(None).await.unwrap();
+    assert_eq!(history.persona().as_deref(), Some("A persona"));
+}
@@ -0,0 +1,498 @@
+use ait_lib::gpt::{ChatCompletionOptions, GptEmbedding, EMBED_DIMS};
+use ait_lib::history::{History, RelatedOptions, Scoring};
+use ait_lib::prompt::{PromptBuilder, PromptOptions};
+use ait_lib::store::{self, FileStore};
+use ait_lib::utils::{text_id_from_hex, text_id_to_hex, TextId};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use base64::{engine::general_purpose, Engine};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::backend::Backend;
+use crate::error::{Error, Result};
+
+pub type ServerHistory = History<EMBED_DIMS>;
+
+/// The state shared by all requests. Readers of the history run
+/// concurrently, and writers persist it before releasing the lock so that
+/// the file on disk follows the order of the writes.
+pub struct AppState<B> {
+    pub history: RwLock<ServerHistory>,
+    pub store: FileStore,
+    pub backend: B,
+}
+
+type SharedState<B> = Arc<AppState<B>>;
+
+impl<B> AppState<B> {
+    /// Store the `history`. It is encoded while locked, and written on a
+    /// blocking thread so that a slow disk doesn't stall other requests.
+    pub async fn persist(&self, history: &ServerHistory) -> Result<()> {
+        let cant_persist = |source| Error::CantPersist {
+            path: self.store.path().to_path_buf(),
+            source,
+        };
+        let data = self
+            .store
+            .format()
+            .serialize(history)
+            .map_err(cant_persist)?;
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || store.write(&data))
+            .await
+            .map_err(|e| cant_persist(store::Error::Failed(e.to_string())))?
+            .map_err(cant_persist)
+    }
+}
+
+pub fn router<B: Backend>(state: SharedState<B>) -> Router {
+    Router::new()
+        .route("/experiences", get(list::<B>).post(push::<B>))
+        .route("/experiences/:id", get(show::<B>).delete(delete::<B>))
+        .route("/related", post(related::<B>))
+        .route("/export", get(export::<B>))
+        .route("/chat", post(chat::<B>))
+        .with_state(state)
+}
+
+fn parse_id(id: &str) -> Result<TextId> {
+    text_id_from_hex(&id.to_lowercase()).ok_or_else(|| Error::InvalidId(id.to_string()))
+}
+
+fn default_context() -> usize {
+    128
+}
+
+fn default_num() -> usize {
+    8
+}
+
+fn default_remember() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExperienceView {
+    pub id: String,
+    pub query: String,
+    pub response: String,
+    pub rank: u32,
+    pub links: Vec<String>,
+}
+
+impl ExperienceView {
+    fn new(history: &ServerHistory, id: &TextId) -> Option<Self> {
+        let experience = history.get(id)?;
+        Some(Self {
+            id: text_id_to_hex(id),
+            query: experience.query.clone(),
+            response: experience.response.clone(),
+            rank: experience.rank,
+            links: history
+                .links(id)
+                .unwrap_or_default()
+                .iter()
+                .map(text_id_to_hex)
+                .collect(),
+        })
+    }
+}
+
+async fn list<B: Backend>(State(state): State<SharedState<B>>) -> Json<Vec<ExperienceView>> {
+    let history = state.history.read().await;
+    let mut experiences: Vec<ExperienceView> = history
+        .iter()
+        .filter_map(|x| ExperienceView::new(&history, &x.id))
+        .collect();
+    experiences.sort_by_key(|x| x.rank);
+    Json(experiences)
+}
+
+async fn show<B: Backend>(
+    State(state): State<SharedState<B>>,
+    Path(id): Path<String>,
+) -> Result<Json<ExperienceView>> {
+    let text_id = parse_id(&id)?;
+    let history = state.history.read().await;
+    ExperienceView::new(&history, &text_id)
+        .map(Json)
+        .ok_or(Error::NoSuchExperience(id))
+}
+
+async fn delete<B: Backend>(
+    State(state): State<SharedState<B>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode> {
+    let text_id = parse_id(&id)?;
+    let mut history = state.history.write().await;
+    history
+        .remove(&text_id)
+        .ok_or(Error::NoSuchExperience(id))?;
+    state.persist(&history).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PushRequest {
+    pub query: String,
+    pub response: String,
+    /// Link to these experiences. By default, links to the experiences
+    /// related to the query.
+    pub links: Option<Vec<String>>,
+    /// The most experiences to link to by default.
+    #[serde(default = "default_context")]
+    pub context: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PushResponse {
+    pub id: String,
+}
+
+async fn push<B: Backend>(
+    State(state): State<SharedState<B>>,
+    Json(request): Json<PushRequest>,
+) -> Result<(StatusCode, Json<PushResponse>)> {
+    let links = request
+        .links
+        .as_ref()
+        .map(|x| x.iter().map(|x| parse_id(x)).collect::<Result<Vec<_>>>())
+        .transpose()?;
+    let exchange = format!("{}\n\n{}", request.query, request.response);
+    let mut embeddings = state
+        .backend
+        .embed_batch(&[request.query.as_str(), &exchange])
+        .await?;
+    let embedding = embeddings.pop();
+    let query_embedding = embeddings.pop();
+    let (embedding, query_embedding) = embedding
+        .zip(query_embedding)
+        .ok_or(ait_lib::gpt::Error::EmptyResponse)?;
+    let mut history = state.history.write().await;
+    let links = match links {
+        Some(links) => links,
+        None => history
+            .related(&query_embedding, request.context)?
+            .into_iter()
+            .map(|x| x.id)
+            .collect(),
+    };
+    let id = history.push(&request.query, &request.response, embedding, links)?;
+    state.persist(&history).await?;
+    let id = text_id_to_hex(&id);
+    Ok((StatusCode::CREATED, Json(PushResponse { id })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RelatedRequest {
+    pub text: String,
+    /// The most experiences to return.
+    #[serde(default = "default_num")]
+    pub num: usize,
+    /// Leave out experiences further than this cosine distance from the text.
+    #[serde(default)]
+    pub max_distance: Option<f32>,
+    /// Re-rank by maximal marginal relevance with this lambda.
+    #[serde(default)]
+    pub mmr_lambda: Option<f32>,
+    /// Rank by a score which favours recent and often retrieved experiences.
+    #[serde(default)]
+    pub scoring: Option<Scoring>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RelatedExperience {
+    #[serde(flatten)]
+    pub experience: ExperienceView,
+    /// The cosine distance from the experience to the text.
+    pub distance: f32,
+}
+
+async fn related<B: Backend>(
+    State(state): State<SharedState<B>>,
+    Json(request): Json<RelatedRequest>,
+) -> Result<Json<Vec<RelatedExperience>>> {
+    let embedding: GptEmbedding = state.backend.embed(&request.text).await?;
+    let history = state.history.read().await;
+    let options = RelatedOptions {
+        max_distance: request.max_distance,
+        mmr_lambda: request.mmr_lambda,
+        scoring: request.scoring,
+        ..Default::default()
+    };
+    let related = history
+        .related_with(&embedding, request.num, &options)?
+        .iter()
+        .filter_map(|x| {
+            Some(RelatedExperience {
+                experience: ExperienceView::new(&history, &x.id)?,
+                distance: x.distance,
+            })
+        })
+        .collect();
+    Ok(Json(related))
+}
+
+/// The history, encoded as in the web demo's storage.
+async fn export<B: Backend>(State(state): State<SharedState<B>>) -> Result<String> {
+    let data = state.history.read().await.serialize()?;
+    Ok(general_purpose::STANDARD_NO_PAD.encode(data))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatRequest {
+    pub query: String,
+    /// How to build the prompt, including the model to prompt and how to
+    /// find the related experiences.
+    #[serde(default)]
+    pub prompt: PromptOptions,
+    /// Push the exchange to the history.
+    #[serde(default = "default_remember")]
+    pub remember: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatResponse {
+    pub response: String,
+    /// The ID of the exchange, if it was pushed to the history.
+    pub id: Option<String>,
+    /// The experiences included in the prompt.
+    pub context: Vec<String>,
+}
+
+async fn chat<B: Backend>(
+    State(state): State<SharedState<B>>,
+    Json(request): Json<ChatRequest>,
+) -> Result<Json<ChatResponse>> {
+    let embedding: GptEmbedding = state.backend.embed(&request.query).await?;
+    let chat_options = ChatCompletionOptions {
+        model: request.prompt.model.clone(),
+        ..Default::default()
+    };
+    // don't hold the lock while waiting on the backend
+    let prompt = {
+        let history = state.history.read().await;
+        PromptBuilder::new(&history, request.prompt).build(&request.query, &embedding)?
+    };
+    let included: Vec<TextId> = prompt
+        .included
+        .iter()
+        .filter_map(|x| prompt.context.get(*x))
+        .copied()
+        .collect();
+    let context_ids = prompt.context;
+    let response = state
+        .backend
+        .chat_completion(prompt.messages, &chat_options)
+        .await?;
+    let embedding = if request.remember {
+        let exchange = format!("{}\n\n{}", request.query, response);
+        Some(state.backend.embed(&exchange).await?)
+    } else {
+        None
+    };
+    let mut history = state.history.write().await;
+    // experiences may have been removed while the history wasn't locked
+    let context_ids: Vec<TextId> = context_ids
+        .into_iter()
+        .filter(|x| history.get(x).is_some())
+        .collect();
+    history.record_retrieval(&included);
+    let id = match embedding {
+        Some(embedding) => Some(history.push(&request.query, &response, embedding, context_ids)?),
+        None => None,
+    };
+    state.persist(&history).await?;
+    Ok(Json(ChatResponse {
+        response,
+        id: id.as_ref().map(text_id_to_hex),
+        context: included.iter().map(text_id_to_hex).collect(),
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::{mock_vector, MockBackend};
+    use ait_lib::embedding::Embedding;
+    use ait_lib::gpt::{
+        self, ChatCompletionMessage, CompletionBackend, EmbeddingBackend, TextCompletionOptions,
+    };
+    use ait_lib::store::HistoryStore;
+    use axum::body::Body;
+    use axum::http::{header, Method, Request};
+    use serde::de::DeserializeOwned;
+    use tokio::sync::Notify;
+    use tower::ServiceExt;
+
+    fn state(name: &str) -> SharedState<MockBackend> {
+        state_with(name, MockBackend)
+    }
+
+    fn state_with<B: Backend>(name: &str, backend: B) -> SharedState<B> {
+        let path = std::env::temp_dir().join(format!("ait_server_{}_{}", name, std::process::id()));
+        Arc::new(AppState {
+            history: RwLock::new(ServerHistory::new()),
+            store: FileStore::new(path),
+            backend,
+        })
+    }
+
+    fn remove_files<B>(state: &SharedState<B>) {
+        let path = state.store.path();
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(path.with_extension("lock")).unwrap();
+    }
+
+    async fn call<B: Backend, T: DeserializeOwned>(
+        state: &SharedState<B>,
+        method: Method,
+        uri: &str,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, Option<T>) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json");
+        let body = body.map(|x| Body::from(x.to_string())).unwrap_or_default();
+        let response = router(state.clone())
+            .oneshot(request.body(body).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).ok())
+    }
+
+    #[tokio::test]
+    async fn serves_experiences() {
+        let state = state("experiences");
+        let body = serde_json::json!({"query": "bake bread", "response": "knead it"});
+        let (status, pushed) =
+            call::<_, PushResponse>(&state, Method::POST, "/experiences", Some(body)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let id = pushed.unwrap().id;
+        let body = serde_json::json!({"query": "sail a boat", "response": "hoist it"});
+        call::<_, PushResponse>(&state, Method::POST, "/experiences", Some(body)).await;
+
+        let uri = format!("/experiences/{}", id);
+        let (status, shown) = call::<_, ExperienceView>(&state, Method::GET, &uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(shown.unwrap().response, "knead it");
+
+        // traverses from the last experience to the first, and ranks the first
+        let body = serde_json::json!({"text": "bread", "num": 2});
+        let (_, related) =
+            call::<_, Vec<RelatedExperience>>(&state, Method::POST, "/related", Some(body)).await;
+        let related = related.unwrap();
+        assert_eq!(related.len(), 2);
+        assert_eq!(related[0].experience.id, id);
+        assert!(related[0].distance < related[1].distance);
+
+        let (status, _) = call::<_, ()>(&state, Method::DELETE, &uri, None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = call::<_, ()>(&state, Method::GET, &uri, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call::<_, ()>(&state, Method::GET, "/experiences/xyz", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // the history on disk follows the writes
+        let stored: ServerHistory = state.store.load().unwrap().unwrap();
+        assert_eq!(stored.len(), 1);
+        remove_files(&state);
+    }
+
+    #[tokio::test]
+    async fn chats_with_context() {
+        let state = state("chat");
+        let body = serde_json::json!({"query": "bake bread"});
+        let (status, first) =
+            call::<_, ChatResponse>(&state, Method::POST, "/chat", Some(body)).await;
+        assert_eq!(status, StatusCode::OK);
+        let first = first.unwrap();
+        assert_eq!(first.response, "You said: bake bread");
+        assert!(first.context.is_empty());
+
+        let body = serde_json::json!({"query": "bake more bread", "remember": false});
+        let (_, second) = call::<_, ChatResponse>(&state, Method::POST, "/chat", Some(body)).await;
+        let second = second.unwrap();
+        assert_eq!(second.id, None);
+        assert_eq!(second.context, vec![first.id.unwrap()]);
+        assert_eq!(state.history.read().await.len(), 1);
+        remove_files(&state);
+    }
+
+    /// The mock backend, which notifies `paused` and waits for `resume`
+    /// before completing a chat.
+    #[derive(Default)]
+    struct PausingBackend {
+        paused: Notify,
+        resume: Notify,
+    }
+
+    impl CompletionBackend for PausingBackend {
+        async fn text_completion(
+            &self,
+            prompt: &str,
+            options: &TextCompletionOptions,
+        ) -> core::result::Result<String, gpt::Error> {
+            MockBackend.text_completion(prompt, options).await
+        }
+
+        async fn chat_completion(
+            &self,
+            messages: Vec<ChatCompletionMessage>,
+            options: &ChatCompletionOptions,
+        ) -> core::result::Result<String, gpt::Error> {
+            self.paused.notify_one();
+            self.resume.notified().await;
+            MockBackend.chat_completion(messages, options).await
+        }
+    }
+
+    impl EmbeddingBackend<EMBED_DIMS> for PausingBackend {
+        async fn embed(&self, text: &str) -> core::result::Result<GptEmbedding, gpt::Error> {
+            MockBackend.embed(text).await
+        }
+    }
+
+    #[tokio::test]
+    async fn chat_drops_links_to_removed_experiences() {
+        let state = state_with("chat_removed", PausingBackend::default());
+        let id = state
+            .history
+            .write()
+            .await
+            .push(
+                "bake bread",
+                "knead it",
+                Embedding::new("", mock_vector("bake bread")),
+                vec![],
+            )
+            .unwrap();
+        let chat = tokio::spawn({
+            let state = state.clone();
+            let body = serde_json::json!({"query": "bake more bread"});
+            async move { call::<_, ChatResponse>(&state, Method::POST, "/chat", Some(body)).await }
+        });
+        // remove the context while the response is generated
+        state.backend.paused.notified().await;
+        let uri = format!("/experiences/{}", text_id_to_hex(&id));
+        let (status, _) = call::<_, ()>(&state, Method::DELETE, &uri, None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        state.backend.resume.notify_one();
+
+        let (status, response) = chat.await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let response = response.unwrap();
+        assert_eq!(response.context, vec![text_id_to_hex(&id)]);
+        let new_id = parse_id(&response.id.unwrap()).unwrap();
+        let history = state.history.read().await;
+        assert!(history.links(&new_id).unwrap().is_empty());
+        remove_files(&state);
+    }
+}
@@ -0,0 +1,91 @@
+use ait_lib::embedding::Embedding;
+use ait_lib::gpt::{
+    self, ChatCompletionMessage, ChatCompletionMessageRole, ChatCompletionOptions,
+    CompletionBackend, EmbeddingBackend, GptEmbedding, TextCompletionOptions, EMBED_DIMS,
+};
+use sha2::{Digest, Sha256};
+
+/// Where the server gets its embeddings and chat completions, such as a
+/// `GptClient` for the OpenAI API, or a service compatible with it.
+pub trait Backend:
+    CompletionBackend + EmbeddingBackend<EMBED_DIMS> + Send + Sync + 'static
+{
+}
+
+impl<T> Backend for T where
+    T: CompletionBackend + EmbeddingBackend<EMBED_DIMS> + Send + Sync + 'static
+{
+}
+
+/// Deterministic stand-ins for the OpenAI API, which need no network.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MockBackend;
+
+impl CompletionBackend for MockBackend {
+    async fn text_completion(
+        &self,
+        prompt: &str,
+        _options: &TextCompletionOptions,
+    ) -> Result<String, gpt::Error> {
+        Ok(format!("You said: {}", prompt))
+    }
+
+    async fn chat_completion(
+        &self,
+        messages: Vec<ChatCompletionMessage>,
+        _options: &ChatCompletionOptions,
+    ) -> Result<String, gpt::Error> {
+        Ok(mock_completion(&messages))
+    }
+}
+
+impl EmbeddingBackend<EMBED_DIMS> for MockBackend {
+    async fn embed(&self, text: &str) -> Result<GptEmbedding, gpt::Error> {
+        Ok(Embedding::new(text, mock_vector(text)))
+    }
+}
+
+/// An embedding vector which counts the words of `text`, each word hashed to
+/// one dimension. Texts sharing words are closer than texts which don't.
+pub fn mock_vector(text: &str) -> [f32; EMBED_DIMS] {
+    let mut vector = [0f32; EMBED_DIMS];
+    for word in text.split(|x: char| !x.is_alphanumeric()) {
+        if word.is_empty() {
+            continue;
+        }
+        let hash = Sha256::digest(word.to_lowercase());
+        let index = u64::from_le_bytes(hash[..8].try_into().unwrap()) as usize % EMBED_DIMS;
+        vector[index] += 1.0;
+    }
+    // the cosine distance to an empty vector isn't defined
+    if vector.iter().all(|x| *x == 0.0) {
+        vector[0] = 1.0;
+    }
+    vector
+}
+
+/// A response which echoes the last user message.
+pub fn mock_completion(messages: &[ChatCompletionMessage]) -> String {
+    let query = messages
+        .iter()
+        .rev()
+        .find(|x| matches!(x.role, ChatCompletionMessageRole::User))
+        .map(|x| x.content.as_str())
+        .unwrap_or_default();
+    format!("You said: {}", query)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mock_vectors_relate_shared_words() {
+        let bread = Embedding::new("", mock_vector("How do I bake bread?"));
+        let loaf = Embedding::new("", mock_vector("Bake a loaf of bread"));
+        let boat = Embedding::new("", mock_vector("Sailing a boat"));
+        assert!(bread.cosine_distance(&loaf) < bread.cosine_distance(&boat));
+        let empty = Embedding::new("", mock_vector(""));
+        assert!(bread.cosine_distance(&empty).is_finite());
+    }
+}
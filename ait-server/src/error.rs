@@ -0,0 +1,88 @@
+use ait_lib::{gpt, history, prompt, store};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::json;
+use std::path::PathBuf;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("no OpenAI API token, set it with --token or OPENAI_API_KEY")]
+    MissingToken,
+    #[error("failed to load the history from {}: {source}", path.display())]
+    CantLoad { path: PathBuf, source: store::Error },
+    #[error("failed to serve: {0}")]
+    CantServe(#[from] hyper::Error),
+    #[error("no experience with ID {0}")]
+    NoSuchExperience(String),
+    #[error("invalid experience ID {0}")]
+    InvalidId(String),
+    #[error("failed to persist the history to {}: {source}", path.display())]
+    CantPersist { path: PathBuf, source: store::Error },
+    #[error(transparent)]
+    History(#[from] history::Error),
+    #[error(transparent)]
+    Gpt(#[from] gpt::Error),
+    #[error(transparent)]
+    Prompt(#[from] prompt::Error),
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+impl Error {
+    /// A short name for the kind of error, given to clients along with the
+    /// message. Errors from the GPT API keep their own kind.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::MissingToken => "MissingToken",
+            Error::CantLoad { .. } => "CantLoad",
+            Error::CantServe(_) => "CantServe",
+            Error::NoSuchExperience(_) => "NoSuchExperience",
+            Error::InvalidId(_) => "InvalidId",
+            Error::CantPersist { .. } => "CantPersist",
+            Error::History(_) => "HistoryError",
+            Error::Gpt(e) => e.kind(),
+            Error::Prompt(_) => "PromptError",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::NoSuchExperience(_) => StatusCode::NOT_FOUND,
+            Error::InvalidId(_) => StatusCode::BAD_REQUEST,
+            Error::MissingToken
+            | Error::CantLoad { .. }
+            | Error::CantServe(_)
+            | Error::CantPersist { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::History(e) => match e {
+                history::Error::CantAccessExperience => StatusCode::NOT_FOUND,
+                history::Error::ExperienceExists => StatusCode::CONFLICT,
+                history::Error::InvalidEmbedding
+                | history::Error::InvalidTextId
+                | history::Error::InvalidOptions(_)
+                | history::Error::IncompatibleEmbedding { .. }
+                | history::Error::Json(_) => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            Error::Gpt(gpt::Error::RateLimited { .. }) => StatusCode::TOO_MANY_REQUESTS,
+            Error::Gpt(gpt::Error::ContextLengthExceeded { .. }) => StatusCode::BAD_REQUEST,
+            Error::Gpt(_) => StatusCode::BAD_GATEWAY,
+            Error::Prompt(prompt::Error::PromptTooLong { .. }) => StatusCode::BAD_REQUEST,
+            Error::Prompt(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Errors are returned in the shape of the OpenAI API's errors, so that
+/// clients can handle both alike.
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let body = json!({
+            "error": {
+                "type": self.kind(),
+                "message": self.to_string(),
+            }
+        });
+        (self.status(), Json(body)).into_response()
+    }
+}
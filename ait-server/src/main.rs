@@ -0,0 +1,90 @@
+use ait_lib::gpt::{GptClient, GptClientConfig, RetryPolicy, DEFAULT_BASE_URL, EMBED_MODEL};
+use ait_lib::store::{FileStore, HistoryStore};
+use axum::Router;
+use clap::Parser;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::api::{AppState, ServerHistory};
+use crate::backend::{Backend, MockBackend};
+use crate::error::{Error, Result};
+
+mod api;
+mod backend;
+mod error;
+mod openai;
+
+#[derive(Debug, Parser)]
+#[command(name = "ait-server", version, about = "Serve an Ait history")]
+struct Args {
+    /// The history file. It is created when missing, and stored as JSON if
+    /// its extension is `json`.
+    #[arg(long, env = "AIT_HISTORY", default_value = "ait_history")]
+    history: PathBuf,
+    /// The address to listen on.
+    #[arg(long, env = "AIT_ADDRESS", default_value = "127.0.0.1:8080")]
+    address: SocketAddr,
+    /// The OpenAI API token.
+    #[arg(long, env = "OPENAI_API_KEY", hide_env_values = true)]
+    token: Option<String>,
+    /// The URL of the OpenAI API, or of a service compatible with it.
+    #[arg(long, env = "OPENAI_BASE_URL", default_value = DEFAULT_BASE_URL)]
+    base_url: String,
+    /// Use deterministic stand-ins for the OpenAI API, and serve them under
+    /// `/v1` so that clients can be tested without a token.
+    #[arg(long)]
+    mock: bool,
+}
+
+async fn serve(args: Args) -> Result<()> {
+    if args.mock {
+        return serve_with(args, MockBackend).await;
+    }
+    let token = args.token.clone().ok_or(Error::MissingToken)?;
+    let config = GptClientConfig {
+        base_url: args.base_url.clone(),
+        retry: Some(RetryPolicy::default()),
+        ..Default::default()
+    };
+    let client = GptClient::with_config(&token, config)?;
+    serve_with(args, client).await
+}
+
+async fn serve_with<B: Backend>(args: Args, backend: B) -> Result<()> {
+    let store = FileStore::new(&args.history);
+    let mut history: ServerHistory = store.load_or_default().map_err(|source| Error::CantLoad {
+        path: args.history,
+        source,
+    })?;
+    if history.embedding_model().is_none() {
+        history.set_embedding_model(Some(EMBED_MODEL.name().to_string()));
+    }
+    let state = Arc::new(AppState {
+        history: RwLock::new(history),
+        store,
+        backend,
+    });
+    let mut app: Router = api::router(state);
+    if args.mock {
+        app = app.nest("/v1", openai::router());
+    }
+    println!("Listening on http://{}", args.address);
+    axum::Server::try_bind(&args.address)?
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match serve(Args::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Stand-ins for the OpenAI API endpoints used by Ait, so that clients can
+//! be tested against this server without a token or network access.
+
+use ait_lib::gpt::ChatCompletionMessage;
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::backend::{mock_completion, mock_vector};
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/chat/completions", post(chat_completions))
+        .route("/embeddings", post(embeddings))
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatCompletionRequest {
+    messages: Vec<ChatCompletionMessage>,
+    #[serde(default)]
+    stream: bool,
+}
+
+async fn chat_completions(Json(request): Json<ChatCompletionRequest>) -> Response {
+    let content = mock_completion(&request.messages);
+    if !request.stream {
+        return Json(json!({
+            "object": "chat.completion",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": content},
+                "finish_reason": "stop",
+            }],
+        }))
+        .into_response();
+    }
+    // the whole response arrives in one chunk, between the role and the end
+    let chunk = |delta: Value, finish_reason: Value| {
+        json!({
+            "object": "chat.completion.chunk",
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+        })
+    };
+    let events = [
+        chunk(json!({"role": "assistant"}), Value::Null),
+        chunk(json!({"content": content}), Value::Null),
+        chunk(json!({}), json!("stop")),
+    ];
+    let mut body: String = events.iter().map(|x| format!("data: {}\n\n", x)).collect();
+    body.push_str("data: [DONE]\n\n");
+    ([(header::CONTENT_TYPE, "text/event-stream")], body).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum EmbeddingInput {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingRequest {
+    input: EmbeddingInput,
+}
+
+async fn embeddings(Json(request): Json<EmbeddingRequest>) -> Json<Value> {
+    let texts = match request.input {
+        EmbeddingInput::One(text) => vec![text],
+        EmbeddingInput::Many(texts) => texts,
+    };
+    let data: Vec<Value> = texts
+        .iter()
+        .enumerate()
+        .map(|(index, text)| {
+            json!({
+                "object": "embedding",
+                "index": index,
+                "embedding": mock_vector(text).to_vec(),
+            })
+        })
+        .collect();
+    Json(json!({
+        "object": "list",
+        "data": data,
+        "model": "text-embedding-ada-002",
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ait_lib::embedding::Embedding;
+    use ait_lib::gpt::{
+        ChatCompletionMessageRole, ChatCompletionOptions, CompletionBackend, EmbeddingBackend,
+        GptClient, GptClientConfig, GptEmbedding,
+    };
+    use futures_util::StreamExt;
+
+    /// Serve the stand-ins on a local port, and a client which calls them.
+    async fn client() -> GptClient {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(Router::new().nest("/v1", router()).into_make_service());
+        tokio::spawn(server);
+        let config = GptClientConfig {
+            base_url: format!("http://{}/v1", address),
+            ..Default::default()
+        };
+        GptClient::with_config("", config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn stands_in_for_openai() {
+        let client = client().await;
+        let embeddings: Vec<GptEmbedding> = client.embed_batch(&["a b", "c"]).await.unwrap();
+        let expected = Embedding::new("", mock_vector("c"));
+        assert!(embeddings[1].cosine_distance(&expected).abs() < 1e-6);
+
+        let messages = || {
+            vec![ChatCompletionMessage {
+                role: ChatCompletionMessageRole::User,
+                content: "hi".to_string(),
+            }]
+        };
+        let options = ChatCompletionOptions::default();
+        let response = client.chat_completion(messages(), &options).await.unwrap();
+        assert_eq!(response, "You said: hi");
+        let chunks: Vec<String> = client
+            .chat_completion_stream(messages(), &options)
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(chunks.concat(), "You said: hi");
+    }
+}
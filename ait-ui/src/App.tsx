@@ -4,52 +4,72 @@ import * as Ait from "ait-lib";
 
 import { SettingsProps } from "./Settings";
 import { WriteQueryProps } from "./WriteQuery";
-import { EditContextProps } from "./EditContext";
+import { EditContextProps, Related } from "./EditContext";
 import { EditResponseProps } from "./EditResponse";
-import { buildExperienceFromId, Embedded, Message } from "./utils";
+import { buildExperienceFromId, describeError, Embedded } from "./utils";
 import { AppAlertProps } from "./AppAlert";
 import { Query, QueryProps } from "./Query";
-import DEFAULT_HISTORY from "./default_history.json";
+import DEFAULT_HISTORY_JSON from "./default_history.json";
+
+const DEFAULT_HISTORY = JSON.stringify(DEFAULT_HISTORY_JSON);
+// the options of `History.related_ids` which find the context
+const CONTEXT_OPTIONS = {
+  // the nearest experiences are in the context even when no links reach them
+  search: { hybrid: { global: 4 } },
+  // trades the relevance of the context for its diversity, so that near
+  // duplicate experiences don't fill it
+  mmr_lambda: 0.7,
+  // favours recent experiences, so that corrections outrank what they correct
+  scoring: { recency_weight: 0.1, half_life: 50 },
+};
 
 export function App() {
-  let [history, setHistory] = useState<Ait.History>(Ait.History.load());
+  let [history, setHistory] = useState<Ait.History>();
   let [query, setQuery] = useState<string>();
-  let [contextIds, setContextIds] = useState<Uint8Array[]>();
+  let [context, setContext] = useState<Related[]>();
   let [response, setResponse] = useState<string>();
   let [token, setToken] = useState<string>();
   let [errorMessage, setErrorMessage] = useState<string | undefined>();
   let [queryLoading, setQueryLoading] = useState<boolean>(false);
   let [contextLoading, setContextLoading] = useState<boolean>(false);
 
-  function storeHistory() {
-    if (document.visibilityState === "hidden") {
-      (async () => {
-        history.store();
-      })().catch((x) => console.error(x));
-    }
-  }
-
-  function clearHistory() {
-    removeEventListener("visibilitychange", storeHistory);
-    window.localStorage.removeItem("ait_history");
-    setResponse(undefined);
-    setContextIds(undefined);
-    setHistory(Ait.History.load());
+  function saveHistory(history: Ait.History) {
+    history.save().catch((x: unknown) => {
+      console.error(x);
+      setErrorMessage(`Unable to save history: ${describeError(x)}`);
+    });
   }
 
-  function resetHistory() {
-    removeEventListener("visibilitychange", storeHistory);
-    window.localStorage.setItem("ait_history", DEFAULT_HISTORY);
+  function replaceHistory(encoded: string | undefined) {
     setResponse(undefined);
-    setContextIds(undefined);
-    setHistory(Ait.History.load());
+    setContext(undefined);
+    Ait.History.replace(encoded)
+      .then(setHistory)
+      .catch((x: unknown) => {
+        console.error(x);
+        setErrorMessage(`Unable to replace history: ${describeError(x)}`);
+      });
   }
 
   useEffect(() => {
-    addEventListener("visibilitychange", storeHistory);
-    return function cleanup() {
-      removeEventListener("visibilitychange", storeHistory);
-    };
+    if (window.indexedDB == null) {
+      // fall back to the smaller `localStorage`, e.g. in private browsing
+      try {
+        setHistory(Ait.History.load());
+      } catch (x: unknown) {
+        console.error(x);
+        setErrorMessage(`Unable to load history: ${describeError(x)}`);
+      }
+      return;
+    }
+    // the default history is kept on the first visit
+    Ait.History.open(DEFAULT_HISTORY)
+      .then(setHistory)
+      .catch((x: unknown) => {
+        // don't fall back, which would hide the history behind an empty one
+        console.error(x);
+        setErrorMessage(`Unable to open history: ${describeError(x)}`);
+      });
   }, []);
 
   useEffect(() => {
@@ -58,10 +78,7 @@ export function App() {
     setToken(token);
   }, []);
 
-  useEffect(() => {
-    if (window.localStorage.getItem("ait_history") != null) return;
-    resetHistory();
-  }, []);
+  if (history == null) return <></>;
 
   let queryDisabledReason = undefined;
   if (token == null) queryDisabledReason = "No API token has been provided.";
@@ -82,28 +99,32 @@ export function App() {
       setQueryLoading(true);
       (async () => {
         const embedding = await Ait.gpt_embed(token, query);
-        setContextIds(history.related_ids(embedding, 128));
+        setContext(
+          history.related_ids(embedding, 128, CONTEXT_OPTIONS)
+        );
         setResponse(undefined);
       })()
         .catch((x) => {
           console.error(x);
-          setErrorMessage("Unable process query.");
+          setErrorMessage(`Unable to process query: ${describeError(x)}`);
         })
         .finally(() => setQueryLoading(false));
     },
     clearQuery: () => {
       setResponse(undefined);
-      setContextIds(undefined);
+      setContext(undefined);
       setQuery(undefined);
     },
   };
 
+  const contextIds = context?.map((x) => x.id);
+
   let contextDisabledReason = undefined;
   if (token == null) contextDisabledReason = "No API token has been provided.";
   else if (contextIds == null) contextDisabledReason = "Context not built.";
 
   const editContextProps: EditContextProps = {
-    contextIds,
+    context,
     disabledReason: contextDisabledReason,
     loading: contextLoading,
     buildExperienceFromId: (id) => buildExperienceFromId(id, history),
@@ -111,20 +132,24 @@ export function App() {
       if (token == null || query == null) return;
       setContextLoading(true);
       (async () => {
-        const messages: Message[] = contextIds.map((x) => ({
-          query: history.get_query(x),
-          response: history.get_response(x),
-          rank: history.get_rank(x),
-        }));
-        messages.sort((a, b) => a.rank - b.rank);
-        messages.push({ query, response: "", rank: 0 });
+        // context is ordered by relevance, so that the least relevant
+        // experiences are dropped if they don't fit in the prompt
+        const messages = history.build_prompt(query, contextIds, undefined);
+        history.record_retrieval(contextIds);
+        saveHistory(history);
         console.info("Messages:\n\n%O", messages);
-        const response = (await Ait.chat_complete(token, messages)).trim();
-        setResponse(response);
+        setResponse(undefined);
+        const response = await Ait.chat_complete_stream(
+          token,
+          messages,
+          undefined,
+          (chunk: string) => setResponse((x) => (x ?? "") + chunk)
+        );
+        setResponse(response.trim());
       })()
         .catch((x) => {
           console.error(x);
-          setErrorMessage("Unable generate response.");
+          setErrorMessage(`Unable to generate response: ${describeError(x)}`);
         })
         .finally(() => setContextLoading(false));
     },
@@ -149,9 +174,13 @@ export function App() {
       (async () => {
         const embedding = await Ait.gpt_embed(token, `${query}\n\n${response}`);
         history.push(query, response, embedding, contextIds);
-      })();
+        saveHistory(history);
+      })().catch((x) => {
+        console.error(x);
+        setErrorMessage(`Unable to store response: ${describeError(x)}`);
+      });
       setQuery(undefined);
-      setContextIds(undefined);
+      setContext(undefined);
       setResponse(undefined);
     },
   };
@@ -167,8 +196,13 @@ export function App() {
         window.localStorage.setItem("ait_token", token);
       }
     },
-    clearHistory,
-    resetHistory,
+    persona: history.persona(),
+    setPersona: (persona: string) => {
+      history.set_persona(persona || undefined);
+      saveHistory(history);
+    },
+    clearHistory: () => replaceHistory(undefined),
+    resetHistory: () => replaceHistory(DEFAULT_HISTORY),
   };
 
   const queryProps: QueryProps = {
@@ -4,8 +4,16 @@ import { Alert, Box, Chip } from "@mui/material";
 import Grid from "@mui/material/Unstable_Grid2";
 import { Experiences, Experience } from "./Experiences";
 
+// An experience related to the query, as returned by `History.related_ids`.
+export interface Related {
+  id: Uint8Array;
+  // The cosine distance from the query, lower is more related.
+  distance: number;
+}
+
 export interface ExperienceTitleProps {
   id: Uint8Array;
+  distance: number;
   dropExperience: (id: Uint8Array) => void;
 }
 
@@ -15,8 +23,14 @@ export function ExperienceTitle(props: ExperienceTitleProps) {
       sx={{
         display: "flex",
         justifyContent: "flex-end",
+        gap: 1,
       }}
     >
+      <Chip
+        label={`Distance ${props.distance.toFixed(3)}`}
+        variant="outlined"
+        size="small"
+      />
       <Chip
         label="Drop"
         color="secondary"
@@ -32,7 +46,7 @@ export function ExperienceTitle(props: ExperienceTitleProps) {
 }
 
 export interface EditContextProps {
-  contextIds: Uint8Array[] | undefined;
+  context: Related[] | undefined;
   disabledReason?: string;
   loading?: boolean;
   buildExperienceFromId: (id: Uint8Array) => Experience;
@@ -42,8 +56,8 @@ export interface EditContextProps {
 export function EditContext(props: EditContextProps) {
   const [removedIds, setRemovedIds] = useState<Set<Uint8Array>>(new Set());
   const [numIds, setNumIds] = useState(3);
-  const contextIds = props.contextIds
-    ? props.contextIds.slice(0, numIds).filter((x) => !removedIds.has(x))
+  const context = props.context
+    ? props.context.slice(0, numIds).filter((x) => !removedIds.has(x.id))
     : undefined;
 
   function dropExperience(id: Uint8Array) {
@@ -53,17 +67,18 @@ export function EditContext(props: EditContextProps) {
   useEffect(() => {
     setNumIds(3);
     setRemovedIds(new Set());
-  }, [props.contextIds]);
+  }, [props.context]);
 
   const experiencesTitles =
-    contextIds && contextIds.length > 0
-      ? contextIds.map((x) => {
-          const experience = props.buildExperienceFromId(x);
+    context && context.length > 0
+      ? context.map((x) => {
+          const experience = props.buildExperienceFromId(x.id);
           return {
             experience,
             title: (
               <ExperienceTitle
                 id={experience.id}
+                distance={x.distance}
                 dropExperience={dropExperience}
               />
             ),
@@ -71,7 +86,7 @@ export function EditContext(props: EditContextProps) {
         })
       : undefined;
 
-  const maxNumIds = props.contextIds ? props.contextIds.length : 0;
+  const maxNumIds = props.context ? props.context.length : 0;
 
   return (
     <>
@@ -80,7 +95,7 @@ export function EditContext(props: EditContextProps) {
           <Experiences experiencesTitles={experiencesTitles} />
         </Box>
       ) : null}
-      {contextIds ? (
+      {context ? (
         <Box sx={{ mb: 1, display: "flex", justifyContent: "center" }}>
           <Chip
             label="More context"
@@ -100,8 +115,8 @@ export function EditContext(props: EditContextProps) {
             variant="contained"
             onClick={(e) => {
               e.preventDefault();
-              if (contextIds == null) return;
-              props.submitContext(contextIds);
+              if (context == null) return;
+              props.submitContext(context.map((x) => x.id));
             }}
             disabled={!!props.disabledReason}
             loading={props.loading}
@@ -6,6 +6,8 @@ import KeyboardReturnIcon from "@mui/icons-material/KeyboardReturn";
 export interface SettingsProps {
   token: string | undefined;
   setToken: (token: string) => void;
+  persona: string | undefined;
+  setPersona: (persona: string) => void;
   clearHistory: () => void;
   resetHistory: () => void;
 }
@@ -19,12 +21,17 @@ export function Settings(props: SettingsProps) {
   }
 
   const [token, setToken] = useState<string>("");
+  const [persona, setPersona] = useState<string>("");
 
   useEffect(() => {
     if (props.token == null) return;
     setToken(props.token);
   }, [props.token]);
 
+  useEffect(() => {
+    setPersona(props.persona ?? "");
+  }, [props.persona]);
+
   return (
     <>
       <TextField
@@ -52,6 +59,16 @@ export function Settings(props: SettingsProps) {
         }}
         sx={{ mb: 1 }}
       />
+      <TextField
+        label="Persona"
+        helperText="Replaces the system prompt. Can use {date}, {user_name} and {experience_count}."
+        value={persona}
+        fullWidth
+        multiline
+        onChange={(e) => setPersona(e.target.value)}
+        onBlur={() => props.setPersona(persona)}
+        sx={{ mb: 1 }}
+      />
       <Button
         variant="contained"
         onClick={(e) => {
@@ -7,12 +7,6 @@ export interface Embedded {
   embedding: Uint8Array;
 }
 
-export interface Message {
-  query: string;
-  response: string;
-  rank: number;
-}
-
 export function buildExperienceFromId(
   id: Uint8Array,
   history: Ait.History
@@ -25,3 +19,8 @@ export function buildExperienceFromId(
     response,
   };
 }
+
+export function describeError(error: unknown): string {
+  if (error instanceof Error) return error.message;
+  return String(error);
+}